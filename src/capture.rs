@@ -0,0 +1,334 @@
+//! Recording the raw bytes of requests, and optionally responses, with
+//! `--capture-dir` so problematic requests can be replayed later.
+
+use crate::http::request::CREDENTIAL_HEADERS;
+use std::{
+    cell::{Cell, RefCell},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How much of one request, or response, is kept. Anything past it is cut
+/// off, so a large upload doesn't end up on disk in full.
+pub(crate) const MAX_CAPTURE_BYTES: usize = 1024 * 1024;
+
+/// How many requests are captured before the server stops capturing, so a
+/// forgotten `--capture-dir` can't fill the disk.
+pub(crate) const MAX_CAPTURES: usize = 1000;
+
+static CAPTURES: AtomicUsize = AtomicUsize::new(0);
+
+type Buffer = Rc<RefCell<Vec<u8>>>;
+
+/// Copies the bytes read from and written to a stream into buffers, where
+/// there are any.
+pub(crate) struct Tee<T> {
+    inner: T,
+    read: Option<Buffer>,
+    written: Option<Buffer>,
+}
+
+impl<T> Tee<T> {
+    pub(crate) fn new(inner: T, read: Option<Buffer>, written: Option<Buffer>) -> Self {
+        Tee {
+            inner,
+            read,
+            written,
+        }
+    }
+}
+
+fn record(buffer: &Option<Buffer>, bytes: &[u8]) {
+    if let Some(buffer) = buffer {
+        let mut buffer = buffer.borrow_mut();
+        let room = MAX_CAPTURE_BYTES.saturating_sub(buffer.len());
+        buffer.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+}
+
+impl<T: Read> Read for Tee<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        record(&self.read, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Tee<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        record(&self.written, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The bytes read from, and written to, one connection since the last save.
+/// Reads are buffered ahead, so the start of a pipelined request can end up
+/// in the capture of the one before it; `redact` covers the whole capture
+/// for that reason.
+pub(crate) struct Capture {
+    dir: PathBuf,
+    responses: bool,
+    request: Buffer,
+    response: Buffer,
+    /// Whether the last capture was cut off, so this one starts part way
+    /// through a line.
+    mid_line: Cell<bool>,
+}
+
+impl Capture {
+    pub(crate) fn new(dir: &Path, responses: bool) -> Self {
+        Capture {
+            dir: dir.to_path_buf(),
+            responses,
+            request: Buffer::default(),
+            response: Buffer::default(),
+            mid_line: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn request_buffer(&self) -> Buffer {
+        self.request.clone()
+    }
+
+    /// The buffer for responses, or `None` if only requests are captured.
+    pub(crate) fn response_buffer(&self) -> Option<Buffer> {
+        self.responses.then(|| self.response.clone())
+    }
+
+    /// Writes out what was captured since the last save, as
+    /// `<unix millis>-<n>.request` and `.response`, and starts over. Failing
+    /// to save is logged rather than failing the connection.
+    pub(crate) fn save(&self) {
+        let mut request = self.request.take();
+        let response = self.response.take();
+        // Anything past MAX_CAPTURE_BYTES went unrecorded, so the next
+        // capture starts part way through a line.
+        let truncated = request.len() >= MAX_CAPTURE_BYTES;
+        let mut mid_line = self.mid_line.get();
+        if mid_line {
+            // There's no telling which header the rest of a line belongs to.
+            match request.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    request.drain(..=end);
+                    mid_line = false;
+                }
+                None => request.clear(),
+            }
+        }
+        self.mid_line.set(truncated || mid_line);
+        if !truncated {
+            // A credential header cut off before its colon can't be
+            // recognised in either capture, so it's kept back for the next.
+            let partial = request.len() - partial_credential_len(&request);
+            self.request.replace(request.split_off(partial));
+        }
+        if request.is_empty() {
+            return;
+        }
+        let n = match claim(&CAPTURES) {
+            Some(n) => n,
+            None => return,
+        };
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let stem = self.dir.join(format!("{}-{:06}", millis, n));
+        let mut result = fs::write(stem.with_extension("request"), redact(&request));
+        if self.responses && result.is_ok() {
+            result = fs::write(stem.with_extension("response"), response);
+        }
+        if let Err(e) = result {
+            eprintln!("Failed to save capture {}: {}", stem.display(), e);
+        }
+    }
+}
+
+/// Takes the next capture number from `captures`, or `None` once
+/// `MAX_CAPTURES` have been taken.
+fn claim(captures: &AtomicUsize) -> Option<usize> {
+    let n = captures.fetch_add(1, Ordering::SeqCst);
+    if n >= MAX_CAPTURES {
+        if n == MAX_CAPTURES {
+            eprintln!("Captured {} requests, not capturing any more", n);
+        }
+        return None;
+    }
+    Some(n)
+}
+
+fn is_credential(name: &[u8]) -> bool {
+    CREDENTIAL_HEADERS
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header.as_bytes()))
+}
+
+/// The length of the unfinished last line of `request` if it could still
+/// turn into a credential header, or 0.
+fn partial_credential_len(request: &[u8]) -> usize {
+    let start = request
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let tail = &request[start..];
+    let could_be_credential = !tail.is_empty()
+        && CREDENTIAL_HEADERS.iter().any(|header| {
+            header.len() >= tail.len()
+                && tail.eq_ignore_ascii_case(&header.as_bytes()[..tail.len()])
+        });
+    if could_be_credential {
+        tail.len()
+    } else {
+        0
+    }
+}
+
+/// Replaces the value of every `Authorization` and `Proxy-Authorization`
+/// header line in a captured request, so credentials don't end up on disk.
+/// Every line is checked, not just the first head, since a pipelined
+/// request read ahead sits in the same capture as the one before it.
+fn redact(request: &[u8]) -> Vec<u8> {
+    let mut redacted = Vec::with_capacity(request.len());
+    for line in request.split_inclusive(|&b| b == b'\n') {
+        match line.iter().position(|&b| b == b':') {
+            Some(colon) if is_credential(&line[..colon]) => {
+                redacted.extend_from_slice(&line[..colon]);
+                redacted.extend_from_slice(b": [redacted]\r\n");
+            }
+            _ => redacted.extend_from_slice(line),
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Duplex, TempDir};
+    use pretty_assertions::assert_eq;
+
+    /// Everything saved under `dir` as requests, oldest first.
+    fn saved_requests(dir: &TempDir) -> Vec<String> {
+        let mut paths: Vec<_> = fs::read_dir(dir.directory())
+            .expect("read capture dir")
+            .map(|entry| entry.expect("entry").path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "request"))
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| String::from_utf8_lossy(&fs::read(path).expect("read")).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn every_credential_line_is_redacted() {
+        let captured = b"GET /a HTTP/1.1\r\nAuthorization: Basic Zmlyc3Q=\r\nHost: x\r\n\r\n\
+            GET /b HTTP/1.1\r\nproxy-authorization: Bearer second\r\nAUTHORIZATION: Basic third\r\n\r\n";
+        assert_eq!(
+            String::from_utf8(redact(captured)).unwrap(),
+            "GET /a HTTP/1.1\r\nAuthorization: [redacted]\r\nHost: x\r\n\r\n\
+            GET /b HTTP/1.1\r\nproxy-authorization: [redacted]\r\nAUTHORIZATION: [redacted]\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn other_lines_are_left_alone() {
+        let captured = b"POST /echo HTTP/1.1\r\nX-Authorization-Hint: none\r\n\r\nauthorization";
+        assert_eq!(redact(captured), captured.to_vec());
+    }
+
+    #[test]
+    fn tee_copies_what_goes_through() {
+        let read = Buffer::default();
+        let written = Buffer::default();
+        let mut tee = Tee::new(
+            Duplex::new("request"),
+            Some(read.clone()),
+            Some(written.clone()),
+        );
+        let mut received = String::new();
+        tee.read_to_string(&mut received).unwrap();
+        tee.write_all(b"response").unwrap();
+
+        assert_eq!(received, "request");
+        assert_eq!(*read.borrow(), b"request");
+        assert_eq!(*written.borrow(), b"response");
+        assert_eq!(tee.inner.output, b"response");
+    }
+
+    #[test]
+    fn tee_without_buffers_only_passes_through() {
+        let mut tee = Tee::new(Duplex::new("request"), None, None);
+        let mut received = String::new();
+        tee.read_to_string(&mut received).unwrap();
+        tee.write_all(b"response").unwrap();
+        assert_eq!(received, "request");
+        assert_eq!(tee.inner.output, b"response");
+    }
+
+    #[test]
+    fn captures_stop_at_max_capture_bytes() {
+        let buffer = Buffer::default();
+        record(&Some(buffer.clone()), &vec![b'a'; MAX_CAPTURE_BYTES - 1]);
+        record(&Some(buffer.clone()), b"bcd");
+        assert_eq!(buffer.borrow().len(), MAX_CAPTURE_BYTES);
+        assert_eq!(buffer.borrow().last(), Some(&b'b'));
+    }
+
+    #[test]
+    fn the_rest_of_a_cut_off_line_is_dropped() {
+        let dir = TempDir::new();
+        let capture = Capture::new(Path::new(&dir.directory()), false);
+        let buffer = Some(capture.request_buffer());
+        record(&buffer, b"POST /files/big HTTP/1.1\r\n\r\n");
+        record(&buffer, &vec![b'a'; MAX_CAPTURE_BYTES]);
+        capture.save();
+        // The cut left off part way through "Authorization: Basic c2VjcmV0".
+        record(&buffer, b"ation: Basic c2VjcmV0\r\nHost: x\r\n\r\n");
+        capture.save();
+
+        let saved = saved_requests(&dir);
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].len(), MAX_CAPTURE_BYTES);
+        assert_eq!(saved[1], "Host: x\r\n\r\n");
+    }
+
+    #[test]
+    fn a_credential_split_between_captures_is_redacted() {
+        let dir = TempDir::new();
+        let capture = Capture::new(Path::new(&dir.directory()), false);
+        let buffer = Some(capture.request_buffer());
+        record(&buffer, b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nAuthor");
+        capture.save();
+        record(&buffer, b"ization: Basic c2VjcmV0\r\n\r\n");
+        capture.save();
+
+        assert_eq!(
+            saved_requests(&dir),
+            [
+                "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n",
+                "Authorization: [redacted]\r\n\r\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn capturing_stops_after_max_captures() {
+        let captures = AtomicUsize::new(0);
+        for n in 0..MAX_CAPTURES {
+            assert_eq!(claim(&captures), Some(n));
+        }
+        assert_eq!(claim(&captures), None);
+        assert_eq!(claim(&captures), None);
+    }
+}
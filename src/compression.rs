@@ -0,0 +1,340 @@
+//! Content-coding negotiation and compression.
+
+use crate::config::Config;
+use crate::http::request::Request;
+use anyhow::Result;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Content codings we can apply to a response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Gzip,
+    /// zlib-wrapped deflate, which is what `deflate` means in HTTP.
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` value, or `None` for an unencoded body.
+    pub(crate) fn token(self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Some("zstd"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Deflate => Some("deflate"),
+            Encoding::Identity => None,
+        }
+    }
+}
+
+/// Bodies smaller than this are sent as they are, whatever the client
+/// accepts.
+pub(crate) const MIN_COMPRESS_SIZE: u64 = 128;
+
+/// Picks the content coding to respond with from the client's
+/// `Accept-Encoding`, honouring q-values. Returns `None` when the client rules
+/// out everything we can produce, identity included. Whether a body may be
+/// compressed on the fly to match is up to `on_the_fly`.
+pub(crate) fn preferred_encoding(request: &Request) -> Option<Encoding> {
+    let Some(accept) = request.headers.get("accept-encoding") else {
+        return Some(Encoding::Identity);
+    };
+
+    let mut codings = Vec::new();
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let coding = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, value)| parse_qvalue(value));
+        // Skip entries whose q-value we can't make sense of.
+        if let Some(q) = q {
+            codings.push((coding, q));
+        }
+    }
+
+    let explicit = |name: &str| {
+        codings
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .map(|(_, q)| *q)
+    };
+    let wildcard = explicit("*");
+    // Identity stays acceptable unless it is ruled out explicitly, or
+    // through the wildcard. When the client doesn't mention it at all it is
+    // only a fallback, so it gets the smallest possible q.
+    let candidates = [
+        #[cfg(feature = "zstd")]
+        (Encoding::Zstd, explicit("zstd").or(wildcard).unwrap_or(0.0)),
+        (Encoding::Gzip, explicit("gzip").or(wildcard).unwrap_or(0.0)),
+        (
+            Encoding::Deflate,
+            explicit("deflate").or(wildcard).unwrap_or(0.0),
+        ),
+        (
+            Encoding::Identity,
+            explicit("identity")
+                .or(wildcard)
+                .unwrap_or(f32::MIN_POSITIVE),
+        ),
+    ];
+
+    // Highest q wins; ties go to the earlier, better compressing, coding.
+    let mut best = None;
+    let mut best_q = 0.0;
+    for (encoding, q) in candidates {
+        if q > best_q {
+            best = Some(encoding);
+            best_q = q;
+        }
+    }
+    best
+}
+
+/// The coding to compress a body with as it is sent, given the `negotiated`
+/// one. At level 0 nothing is compressed on the fly, and neither is a
+/// response to HTTP/1.0 with `compress_http11_only`, since some of those
+/// clients advertise codings they then fail to decode. A body stored
+/// compressed already can still go out as negotiated.
+pub(crate) fn on_the_fly(negotiated: Encoding, request: &Request, config: &Config) -> Encoding {
+    if config.compression_level == 0
+        || (config.compress_http11_only && request.version == "HTTP/1.0")
+    {
+        return Encoding::Identity;
+    }
+    negotiated
+}
+
+/// Whether compressing a body is likely to pay off. Below
+/// `MIN_COMPRESS_SIZE` the framing overhead outweighs any saving, and formats
+/// that are compressed already only get bigger.
+pub(crate) fn worth_compressing(content_type: Option<&str>, len: Option<u64>) -> bool {
+    if len.is_some_and(|len| len < MIN_COMPRESS_SIZE) {
+        return false;
+    }
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let compressed = (media_type.starts_with("image/") && media_type != "image/svg+xml")
+        || media_type.starts_with("audio/")
+        || media_type.starts_with("video/")
+        || matches!(
+            media_type.as_str(),
+            "application/gzip" | "application/zip" | "application/zstd" | "font/woff2"
+        );
+    !compressed
+}
+
+/// Parses a q-value, which has to lie between 0 and 1.
+pub(crate) fn parse_qvalue(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if !value.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|q| (0.0..=1.0).contains(q))
+}
+
+/// Compresses `body` with `encoding`, unless `on_the_fly` rules that out
+/// for `request`, returning the bytes to send and the `Content-Encoding` to
+/// label them with.
+pub(crate) fn maybe_compress(
+    body: &[u8],
+    encoding: Encoding,
+    request: &Request,
+    config: &Config,
+) -> Result<(Vec<u8>, Option<&'static str>)> {
+    let encoding = on_the_fly(encoding, request, config);
+    let level = Compression::new(config.compression_level);
+    let compressed = match encoding {
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd => zstd::encode_all(body, config.zstd_level)?,
+        Encoding::Gzip => compress_gzip(body, level)?,
+        Encoding::Deflate => compress_deflate(body, level)?,
+        Encoding::Identity => body.to_vec(),
+    };
+    Ok((compressed, encoding.token()))
+}
+
+/// Compresses whatever is read from `reader` with `encoding` as it goes,
+/// for a body too large to compress in memory.
+pub(crate) fn compress_reader<R: Read + Send + 'static>(
+    reader: R,
+    encoding: Encoding,
+    config: &Config,
+) -> Result<Box<dyn Read + Send>> {
+    let level = Compression::new(config.compression_level);
+    Ok(match encoding {
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, config.zstd_level)?),
+        Encoding::Gzip => Box::new(flate2::read::GzEncoder::new(reader, level)),
+        Encoding::Deflate => Box::new(flate2::read::ZlibEncoder::new(reader, level)),
+        Encoding::Identity => Box::new(reader),
+    })
+}
+
+pub(crate) fn compress_gzip(data: &[u8], level: Compression) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish().map_err(Into::into)
+}
+
+pub(crate) fn compress_deflate(data: &[u8], level: Compression) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish().map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::request;
+
+    /// The coding picked for a client sending `accept` as its
+    /// `Accept-Encoding`.
+    fn negotiate(accept: &str) -> Option<Encoding> {
+        let request = request(&format!(
+            "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+            accept
+        ));
+        preferred_encoding(&request)
+    }
+
+    /// The coding a client accepting anything gets.
+    #[cfg(feature = "zstd")]
+    const BEST: Encoding = Encoding::Zstd;
+    #[cfg(not(feature = "zstd"))]
+    const BEST: Encoding = Encoding::Gzip;
+
+    /// The coding a client accepting anything but gzip gets.
+    #[cfg(feature = "zstd")]
+    const BEST_BUT_GZIP: Encoding = Encoding::Zstd;
+    #[cfg(not(feature = "zstd"))]
+    const BEST_BUT_GZIP: Encoding = Encoding::Deflate;
+
+    #[test]
+    fn gzip_at_q0_is_refused() {
+        assert_eq!(negotiate("gzip;q=0"), Some(Encoding::Identity));
+        assert_eq!(negotiate("gzip;q=0, deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn the_better_of_equally_wanted_codings_wins() {
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn a_wildcard_accepts_every_coding() {
+        assert_eq!(negotiate("*"), Some(BEST));
+        assert_eq!(negotiate("*;q=0"), None);
+    }
+
+    #[test]
+    fn the_highest_q_value_wins() {
+        assert_eq!(
+            negotiate("gzip;q=0.5, deflate;q=0.8"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("deflate;q=0.1, gzip;q=1.0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.001"), Some(Encoding::Gzip));
+        // An unreadable q-value drops only its own entry.
+        assert_eq!(negotiate("gzip;q=high, deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn a_wildcard_covers_only_what_isnt_named() {
+        assert_eq!(negotiate("gzip;q=0, *"), Some(BEST_BUT_GZIP));
+        assert_eq!(negotiate("*;q=0.5, deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn whitespace_and_case_are_ignored() {
+        assert_eq!(
+            negotiate("  GZIP ; Q=0.9 ,deflate;q=0.2"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("identity ,\tdeflate\t"), Some(Encoding::Deflate));
+        assert_eq!(negotiate(" , gzip , "), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn refusing_identity_and_every_coding_is_not_acceptable() {
+        assert_eq!(negotiate("identity;q=0"), None);
+        assert_eq!(negotiate("br, identity;q=0"), None);
+        assert_eq!(negotiate("br, *;q=0"), None);
+        assert_eq!(negotiate("identity;q=0, deflate"), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn http_1_0_clients_can_be_kept_to_identity() {
+        let http11_only = Config {
+            compress_http11_only: true,
+            ..Config::default()
+        };
+        let client = |version: &str| {
+            request(&format!(
+                "GET / {}\r\nAccept-Encoding: gzip\r\n\r\n",
+                version
+            ))
+        };
+
+        let gzip = Encoding::Gzip;
+        assert_eq!(
+            on_the_fly(gzip, &client("HTTP/1.0"), &http11_only),
+            Encoding::Identity
+        );
+        assert_eq!(on_the_fly(gzip, &client("HTTP/1.1"), &http11_only), gzip);
+        assert_eq!(
+            on_the_fly(gzip, &client("HTTP/1.0"), &Config::default()),
+            gzip
+        );
+    }
+
+    #[test]
+    fn higher_levels_compress_no_worse() {
+        let text: String = (0..2000)
+            .map(|n| format!("line {} of {}\n", n * 7919 % 1000, n % 13))
+            .collect();
+        let size = |level: u32| {
+            compress_gzip(text.as_bytes(), Compression::new(level))
+                .unwrap()
+                .len()
+        };
+
+        assert!(size(9) <= size(6), "{} > {}", size(9), size(6));
+        assert!(size(6) <= size(1), "{} > {}", size(6), size(1));
+        assert!(size(1) < text.len());
+    }
+
+    #[test]
+    fn level_0_turns_compression_off() {
+        let off = Config {
+            compression_level: 0,
+            ..Config::default()
+        };
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert_eq!(preferred_encoding(&request), Some(Encoding::Gzip));
+        assert_eq!(
+            on_the_fly(Encoding::Gzip, &request, &off),
+            Encoding::Identity
+        );
+        assert_eq!(
+            maybe_compress(b"a body to leave alone", Encoding::Gzip, &request, &off).unwrap(),
+            (b"a body to leave alone".to_vec(), None)
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Server settings, filled in from the command line.
+
+use crate::auth::Credentials;
+use crate::stats::Stats;
+use rustls::ServerConfig;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+pub const DEFAULT_DIRECTORY: &str = ".";
+pub const DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 4221;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+pub const DEFAULT_RETRY_AFTER: u64 = 300;
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+pub const DEFAULT_MAX_RESPONSE_HEADERS: usize = 16 * 1024;
+pub const DEFAULT_INDEX_FILES: [&str; 2] = ["index.html", "index.htm"];
+/// Each worker stays with one connection until it closes or idles out, so
+/// the pool is sized for concurrent connections rather than CPUs.
+pub const DEFAULT_THREADS: usize = 64;
+
+/// What to do with a body sent along with a GET or DELETE request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyPolicy {
+    /// Read and discard it.
+    Drain,
+    /// Answer with 400 Bad Request.
+    Reject,
+}
+
+/// How ETags for served files are derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EtagMode {
+    /// A hash of the contents. Stable across restarts and redeploys, at the
+    /// cost of reading each file once per change.
+    Content,
+    /// The size and modification time. Free, but changes whenever the file
+    /// is touched, even if the contents are identical.
+    Mtime,
+}
+
+/// Which values of a query parameter given more than once are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateQuery {
+    /// All of them, in order.
+    KeepAll,
+    First,
+    Last,
+}
+
+/// A `--force-connection` override of whether connections persist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceConnection {
+    Close,
+    KeepAlive,
+}
+
+/// How much the server prints. Errors always go to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    /// One access line per request on stdout.
+    Info,
+    /// Also the headers of every request.
+    Debug,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub directory: String,
+    /// Handle a single request and exit instead of serving forever.
+    pub one_shot: bool,
+    pub body_policy: BodyPolicy,
+    pub duplicate_query: DuplicateQuery,
+    /// The largest request body accepted, in bytes. Anything bigger gets a
+    /// 413.
+    pub max_body: usize,
+    pub secure_headers: bool,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<Arc<ServerConfig>>,
+    /// How many bytes of headers a response may have. Past that, all but
+    /// the essential ones are dropped.
+    pub max_response_headers: usize,
+    /// Headers from `--header`, added to every response after everything else
+    /// so they can override the defaults.
+    pub extra_headers: Vec<(String, String)>,
+    /// Number of worker threads, and so the number of connections served at
+    /// once; further connections wait for a free worker.
+    pub threads: usize,
+    /// Limits how fast files are sent to each client IP, in bytes per
+    /// second, shared between all the downloads to it.
+    pub download_rate: Option<u64>,
+    /// Hint sequential access to the kernel for served files (Linux only).
+    pub fadvise: bool,
+    pub etag_mode: EtagMode,
+    /// How long a read from, or write to, a client may block. This is also
+    /// how long an idle persistent connection is kept open, and how long a
+    /// client has to send the head of a request once it has started.
+    pub timeout: Duration,
+    /// Idle time before the OS starts probing a connection with TCP
+    /// keepalives, so dead peers get noticed.
+    pub tcp_keepalive: Option<Duration>,
+    /// Overrides `timeout` for writes to a client that stopped reading.
+    pub write_timeout: Option<Duration>,
+    /// Write timeouts for responses to paths under these prefixes, e.g. a
+    /// longer one for large downloads from `/files`. The longest matching
+    /// prefix wins over a timeout registered with the route, `write_timeout`
+    /// and `timeout`.
+    pub route_timeouts: Vec<(String, Duration)>,
+    /// `Cache-Control` values for served files by media type, such as
+    /// `text/html` or `image/*`. The first matching rule wins.
+    pub cache_control: Vec<(String, String)>,
+    /// Require Basic auth from one of these users for every request.
+    pub credentials: Option<Arc<Credentials>>,
+    /// Send 103 Early Hints for files that have an `.early-hints` sidecar.
+    pub early_hints: bool,
+    #[cfg(feature = "zstd")]
+    pub zstd_level: i32,
+    /// The gzip and deflate level, from 0 to 9. At 0 nothing is compressed
+    /// on the fly, whatever the client accepts.
+    pub compression_level: u32,
+    /// Only compress responses to HTTP/1.1 requests, sending HTTP/1.0
+    /// clients identity whatever they accept.
+    pub compress_http11_only: bool,
+    /// Serve a gzip-accepting client the `.gz` sidecar of a file, where
+    /// there is one, instead of compressing the file on the fly.
+    pub precompressed: bool,
+    pub log_level: LogLevel,
+    /// Answer GET on a directory under `/files/` with a listing instead of
+    /// a 403.
+    pub enable_listing: bool,
+    /// Tried in order when a directory is requested, the first that exists
+    /// being served.
+    pub index_files: Vec<String>,
+    /// Serve `<path>.html` for a `/files/<path>` without an extension that
+    /// doesn't exist.
+    pub static_site: bool,
+    /// A replacement for `DEFAULT_LISTING_TEMPLATE`, checked at startup.
+    pub listing_template: Option<String>,
+    /// The origin allowed to make cross-origin requests, or `*` for any.
+    pub cors_origin: Option<String>,
+    /// Answer everything but the health checks with 503.
+    pub maintenance: bool,
+    /// The `Retry-After` seconds sent with maintenance responses.
+    pub retry_after: u64,
+    /// HTML served as the body of maintenance responses.
+    pub maintenance_page: Option<String>,
+    /// Serve `/manifest.json`, listing every file with its size and hash.
+    pub manifest: bool,
+    /// Require single spaces between the parts of the request line.
+    pub strict_request_line: bool,
+    /// Overrides what the client asked for in its `Connection` header. The
+    /// server still closes when it has to: in one-shot mode, when shutting
+    /// down, or when the body is delimited by closing. Forcing keep-alive on
+    /// clients or proxies that don't expect it can desync them, as they may
+    /// treat what follows a response as part of it.
+    pub force_connection: Option<ForceConnection>,
+    /// Save the raw bytes of every request to files in this directory.
+    pub capture_dir: Option<PathBuf>,
+    /// Save the responses next to the captured requests too.
+    pub capture_responses: bool,
+    /// How long to wait for in-flight connections once shutdown starts.
+    pub grace_period: Duration,
+    /// Set when the server starts shutting down. Persistent connections are
+    /// closed after their current response from then on.
+    pub shutdown: Arc<AtomicBool>,
+    pub stats: Arc<Stats>,
+}
+
+impl Config {
+    /// How long a write of the response for `path` may block, given the
+    /// timeout registered with its route, if any.
+    pub(crate) fn write_timeout_for(
+        &self,
+        path: &str,
+        route_timeout: Option<Duration>,
+    ) -> Duration {
+        self.route_timeouts
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, timeout)| *timeout)
+            .or(route_timeout)
+            .or(self.write_timeout)
+            .unwrap_or(self.timeout)
+    }
+
+    /// The `Cache-Control` for a file served as `content_type`, if a rule
+    /// covers it.
+    pub(crate) fn cache_control_for(&self, content_type: &str) -> Option<&str> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.cache_control
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_suffix("/*") {
+                Some("*") => true,
+                Some(kind) => media_type
+                    .split_once('/')
+                    .is_some_and(|(media_kind, _)| media_kind.eq_ignore_ascii_case(kind)),
+                None => pattern.eq_ignore_ascii_case(media_type),
+            })
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: DEFAULT_ADDRESS,
+            port: DEFAULT_PORT,
+            directory: DEFAULT_DIRECTORY.to_string(),
+            one_shot: false,
+            body_policy: BodyPolicy::Drain,
+            duplicate_query: DuplicateQuery::KeepAll,
+            max_body: DEFAULT_MAX_BODY_SIZE,
+            secure_headers: false,
+            tls: None,
+            max_response_headers: DEFAULT_MAX_RESPONSE_HEADERS,
+            extra_headers: Vec::new(),
+            threads: DEFAULT_THREADS,
+            download_rate: None,
+            fadvise: false,
+            etag_mode: EtagMode::Mtime,
+            timeout: DEFAULT_TIMEOUT,
+            write_timeout: None,
+            tcp_keepalive: None,
+            route_timeouts: Vec::new(),
+            cache_control: Vec::new(),
+            credentials: None,
+            early_hints: false,
+            #[cfg(feature = "zstd")]
+            zstd_level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            compress_http11_only: false,
+            precompressed: false,
+            log_level: LogLevel::Info,
+            enable_listing: false,
+            index_files: DEFAULT_INDEX_FILES.map(String::from).to_vec(),
+            static_site: false,
+            listing_template: None,
+            cors_origin: None,
+            maintenance: false,
+            retry_after: DEFAULT_RETRY_AFTER,
+            maintenance_page: None,
+            manifest: false,
+            strict_request_line: true,
+            force_connection: None,
+            capture_dir: None,
+            capture_responses: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(Stats::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn cache_control_rules_match_wildcard_types() {
+        let config = Config {
+            cache_control: vec![
+                ("text/html".into(), "no-cache".into()),
+                ("image/*".into(), "public, max-age=604800".into()),
+                ("*/*".into(), "max-age=60".into()),
+            ],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.cache_control_for("image/png"),
+            Some("public, max-age=604800")
+        );
+        assert_eq!(
+            config.cache_control_for("IMAGE/svg+xml"),
+            Some("public, max-age=604800")
+        );
+        assert_eq!(
+            config.cache_control_for("text/html; charset=utf-8"),
+            Some("no-cache")
+        );
+        assert_eq!(config.cache_control_for("text/css"), Some("max-age=60"));
+        // An image-ish name without the slash is no image.
+        assert_eq!(
+            Config {
+                cache_control: vec![("image/*".into(), "public".into())],
+                ..Config::default()
+            }
+            .cache_control_for("imagefile"),
+            None
+        );
+        assert_eq!(Config::default().cache_control_for("image/png"), None);
+    }
+}
@@ -0,0 +1,577 @@
+//! Serving the requests on one client connection.
+
+use crate::capture::{Capture, Tee};
+use crate::config::{Config, ForceConnection, LogLevel};
+use crate::escape::log_escape;
+use crate::handlers::{is_health_check, maintenance_response, ALL_METHODS};
+use crate::http::request::{read_request, read_request_body, Request, RequestError, RequestLine};
+use crate::http::response::{error_response, Response};
+use crate::http::*;
+use crate::router::Router;
+use anyhow::Result;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+/// Added to every response with `--secure-headers`. Strict-Transport-Security
+/// is only added over TLS, where it means something. Expect-CT is left out:
+/// it is deprecated, and browsers enforce Certificate Transparency without it.
+pub(crate) const SECURE_HEADERS: [(&str, &str); 3] = [
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+];
+
+/// A client connection, read through a buffer and written to directly.
+struct ClientStream<S> {
+    reader: BufReader<Tee<S>>,
+}
+
+impl<S: Read> Read for ClientStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<S: Read> BufRead for ClientStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount)
+    }
+}
+
+impl<S: Write> Write for ClientStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.reader.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.reader.get_mut().flush()
+    }
+}
+
+/// Serves the requests on an accepted connection with `router`, over TLS
+/// if the server has a certificate.
+pub fn handle_client(stream: TcpStream, config: &Config, router: &Router) -> Result<()> {
+    let socket = stream.try_clone()?;
+    match &config.tls {
+        Some(tls) => handle_tls_client(stream, &socket, tls, config, router),
+        None => serve_connection(stream, &socket, None, config, router),
+    }
+}
+
+/// Completes the TLS handshake before serving the connection. A failed
+/// handshake is logged and the connection dropped.
+fn handle_tls_client(
+    stream: TcpStream,
+    socket: &TcpStream,
+    tls: &Arc<ServerConfig>,
+    config: &Config,
+    router: &Router,
+) -> Result<()> {
+    socket.set_read_timeout(Some(config.timeout))?;
+    socket.set_write_timeout(Some(config.timeout))?;
+    let mut session = StreamOwned::new(ServerConnection::new(Arc::clone(tls))?, stream);
+    while session.conn.is_handshaking() {
+        if let Err(e) = session.conn.complete_io(&mut session.sock) {
+            eprintln!("TLS handshake failed: {}", e);
+            return Ok(());
+        }
+    }
+
+    let server_name = session.conn.server_name().map(str::to_string);
+    let result = serve_connection(&mut session, socket, server_name.as_deref(), config, router);
+    session.conn.send_close_notify();
+    let _ = session.flush();
+    result
+}
+
+/// The request loop, over plain TCP or TLS. `socket` is the underlying
+/// connection, for timeouts and the peer address. `server_name` is the
+/// name the client asked for in the TLS handshake, if any.
+fn serve_connection<S: Read + Write>(
+    stream: S,
+    socket: &TcpStream,
+    server_name: Option<&str>,
+    config: &Config,
+    router: &Router,
+) -> Result<()> {
+    let capture = config
+        .capture_dir
+        .as_deref()
+        .map(|dir| Capture::new(dir, config.capture_responses));
+    let mut conn = ClientStream {
+        reader: BufReader::new(Tee::new(
+            stream,
+            capture.as_ref().map(Capture::request_buffer),
+            capture.as_ref().and_then(Capture::response_buffer),
+        )),
+    };
+    let save_capture = || {
+        if let Some(capture) = &capture {
+            capture.save();
+        }
+    };
+    socket.set_read_timeout(Some(config.timeout))?;
+    socket.set_write_timeout(Some(config.write_timeout.unwrap_or(config.timeout)))?;
+    let peer_ip = socket.peer_addr().ok().map(|addr| addr.ip());
+    let peer = socket
+        .peer_addr()
+        .map_or_else(|_| "-".to_string(), |addr| addr.to_string());
+
+    loop {
+        let started = Instant::now();
+        let mut request = match read_request(&mut conn, config) {
+            Ok(Some(mut request)) => {
+                config.stats.record_request();
+                request.peer = peer_ip;
+                request
+            }
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                if reject_unreadable(&mut conn, err, config, &peer, started)? {
+                    config.stats.record_request();
+                }
+                save_capture();
+                return Ok(());
+            }
+        };
+        if config.log_level >= LogLevel::Debug {
+            println!(
+                "{} {} {} headers={:?}",
+                peer,
+                log_escape(&request.method),
+                log_escape(&request.path),
+                request.headers
+            );
+        }
+
+        let mut response = refusal(&request, server_name, config);
+        // A client waiting for 100 Continue only gets it, and only sends the
+        // body, once the request is known to be accepted. Otherwise the body
+        // is never read, so the connection can't be reused.
+        let mut body_unread = false;
+        if request.expects_continue() {
+            response = response.or_else(|| router.reject_early(&request, config));
+            body_unread = response.is_some();
+            if !body_unread {
+                if let Err(err) = read_request_body(&mut conn, &mut request, config) {
+                    reject_unreadable(&mut conn, err, config, &peer, started)?;
+                    save_capture();
+                    return Ok(());
+                }
+            }
+        }
+        let mut response = response.unwrap_or_else(|| router.dispatch(&request, config));
+
+        apply_response_headers(&mut response, config);
+        let write_timeout = config.write_timeout_for(&request.path, router.timeout_for(&request));
+        socket.set_write_timeout(Some(write_timeout))?;
+
+        // HTTP/1.0 clients don't understand chunked encoding, so a streamed
+        // body of unknown length has to be delimited by closing instead.
+        let chunked = request.version == "HTTP/1.1";
+        if request.method == "HEAD" {
+            response.strip_body(chunked);
+        }
+        let can_keep_alive = !config.one_shot
+            && !body_unread
+            && !config.shutdown.load(Ordering::SeqCst)
+            && (chunked || response.body.len().is_some());
+        let keep_alive = match config.force_connection {
+            Some(ForceConnection::Close) => false,
+            Some(ForceConnection::KeepAlive) => can_keep_alive,
+            None => can_keep_alive && request.keep_alive(),
+        };
+        if !keep_alive {
+            response.set_header("Connection", "close");
+        } else if config.force_connection.is_some() {
+            response.set_header("Connection", "keep-alive");
+        }
+
+        // HTTP/1.0 clients may not expect an interim response.
+        if config.early_hints && request.version == "HTTP/1.1" && !response.early_hints.is_empty() {
+            write_early_hints(&mut conn, &response.early_hints)?;
+        }
+
+        let status = response.status_code();
+        let sent = match response
+            .write_to(&mut conn, chunked)
+            .and_then(|sent| conn.flush().map(|_| sent))
+        {
+            Ok(sent) => sent,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                eprintln!("Client stopped reading, disconnecting");
+                save_capture();
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        config.stats.record_response(status, sent);
+        save_capture();
+
+        log_access(
+            config,
+            &peer,
+            &request.method,
+            &request.path,
+            status,
+            sent,
+            started,
+        );
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// The response for a request turned away whatever its route: sent to the
+/// wrong host, during maintenance, or without valid credentials.
+fn refusal(request: &Request, server_name: Option<&str>, config: &Config) -> Option<Response> {
+    // Browsers never send credentials with a CORS preflight.
+    let authorized = match &config.credentials {
+        Some(_) if request.method == "OPTIONS" => true,
+        Some(credentials) => credentials.verify(&request.headers),
+        None => true,
+    };
+    // A connection-coalescing client may send a request for another host
+    // over a connection set up for this one.
+    let misdirected = match (server_name, request.headers.get("host")) {
+        (Some(name), Some(host)) => !host_name(host).eq_ignore_ascii_case(name),
+        _ => false,
+    };
+    if misdirected {
+        Some(error_response(StatusCode::MisdirectedRequest))
+    } else if config.maintenance && !is_health_check(&request.path) {
+        Some(maintenance_response(config))
+    } else if !authorized {
+        let mut response = error_response(StatusCode::Unauthorized);
+        response.headers.push((
+            "WWW-Authenticate".to_string(),
+            "Basic realm=\"files\", charset=\"UTF-8\"".to_string(),
+        ));
+        Some(response)
+    } else {
+        None
+    }
+}
+
+/// Answers a request that couldn't be read in full, then gives up on the
+/// connection. Returns whether there was a request to answer: running out
+/// of time before the end of a request is the client's problem and gets a
+/// 408, but nobody is left to answer when the client went away.
+fn reject_unreadable<C: Write>(
+    conn: &mut C,
+    err: anyhow::Error,
+    config: &Config,
+    peer: &str,
+    started: Instant,
+) -> Result<bool> {
+    let status = if let Some(request_err) = err.downcast_ref::<RequestError>() {
+        eprintln!("Rejecting request: {}", request_err);
+        request_err.status()
+    } else if is_timeout(&err) {
+        eprintln!("Timed out reading request");
+        StatusCode::RequestTimeout
+    } else if is_disconnect(&err) {
+        if config.log_level >= LogLevel::Debug {
+            println!("{} disconnected mid-request: {:#}", peer, err);
+        }
+        return Ok(false);
+    } else {
+        return Err(err);
+    };
+    let mut response = error_response(status);
+    response
+        .headers
+        .push(("Connection".to_string(), "close".to_string()));
+    apply_response_headers(&mut response, config);
+    let status = response.status_code();
+    let sent = response.write_to(&mut *conn, false)?;
+    conn.flush()?;
+    config.stats.record_response(status, sent);
+    let (method, path) = match err.downcast_ref::<RequestLine>() {
+        Some(line) => (line.method.as_str(), line.path.as_str()),
+        None => ("-", "-"),
+    };
+    log_access(config, peer, method, path, status, sent, started);
+    Ok(true)
+}
+
+/// The host in a `Host` header, without the port.
+pub(crate) fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Prints the access line for one request.
+pub(crate) fn log_access(
+    config: &Config,
+    peer: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    sent: u64,
+    started: Instant,
+) {
+    if config.log_level >= LogLevel::Info {
+        println!(
+            "{}",
+            access_line(peer, method, path, status, sent, started.elapsed())
+        );
+    }
+}
+
+/// The access line for one request, e.g.
+/// `127.0.0.1:50000 GET /echo/abc 200 3 0ms`. The method and path are
+/// escaped, so the line is always one line.
+fn access_line(
+    peer: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    sent: u64,
+    elapsed: Duration,
+) -> String {
+    format!(
+        "{} {} {} {} {} {}ms",
+        peer,
+        log_escape(method),
+        log_escape(path),
+        status,
+        sent,
+        elapsed.as_millis()
+    )
+}
+
+/// Sends a 103 Early Hints interim response so the client can start
+/// fetching the linked resources while the real response is prepared.
+pub(crate) fn write_early_hints<W: Write>(writer: &mut W, links: &[String]) -> io::Result<()> {
+    let mut hints = String::from("HTTP/1.1 103 Early Hints\r\n");
+    for link in links {
+        hints.push_str(&format!("Link: {}\r\n", link));
+    }
+    hints.push_str("\r\n");
+    writer.write_all(hints.as_bytes())?;
+    writer.flush()
+}
+
+/// Picks the status for a handler that failed. Filesystem errors with an
+/// obvious meaning get their own code, anything else is a 500.
+pub(crate) fn handler_error_status(err: &anyhow::Error) -> StatusCode {
+    let kind = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map(io::Error::kind);
+    match kind {
+        Some(io::ErrorKind::PermissionDenied) => StatusCode::Forbidden,
+        Some(io::ErrorKind::NotFound) => StatusCode::NotFound,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+/// Whether the client went away, by resetting the connection or closing it
+/// before the request was complete.
+pub(crate) fn is_disconnect(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+pub(crate) fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+    })
+}
+
+/// Adds the headers configured for every response, whatever the handler.
+pub fn apply_response_headers(response: &mut Response, config: &Config) {
+    if config.secure_headers {
+        for (name, value) in SECURE_HEADERS {
+            response.set_header(name, value);
+        }
+        if config.tls.is_some() {
+            response.set_header("Strict-Transport-Security", "max-age=31536000");
+        }
+    }
+    if let Some(origin) = &config.cors_origin {
+        response.set_header("Access-Control-Allow-Origin", origin);
+        // A preflight has already named the methods and headers of its
+        // route.
+        if !response.has_header("Access-Control-Allow-Methods") {
+            response.set_header("Access-Control-Allow-Methods", &ALL_METHODS.join(", "));
+        }
+        if !response.has_header("Access-Control-Allow-Headers") {
+            response.set_header("Access-Control-Allow-Headers", "Content-Type");
+        }
+    }
+    for (name, value) in &config.extra_headers {
+        response.set_header(name, value);
+    }
+    let dropped = response.limit_headers(config.max_response_headers);
+    if dropped > 0 {
+        eprintln!(
+            "Dropped {} response header(s) over the {} byte budget",
+            dropped, config.max_response_headers
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Duplex, Wire};
+    use pretty_assertions::assert_eq;
+
+    /// A connection that sends `head` and is then reset.
+    struct ResetAfter(io::Cursor<Vec<u8>>);
+
+    impl ResetAfter {
+        fn new(head: &str) -> Self {
+            ResetAfter(io::Cursor::new(head.as_bytes().to_vec()))
+        }
+    }
+
+    impl Read for ResetAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.fill_buf()?.read(buf)?;
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for ResetAfter {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if self.0.position() as usize == self.0.get_ref().len() {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            self.0.fill_buf()
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.0.consume(amount)
+        }
+    }
+
+    impl Write for ResetAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What the connection sends back for a request that failed to read.
+    fn answer_to(err: anyhow::Error) -> (bool, Vec<u8>) {
+        let mut output = Vec::new();
+        let answered = reject_unreadable(
+            &mut output,
+            err,
+            &Config::default(),
+            "127.0.0.1:50000",
+            Instant::now(),
+        )
+        .expect("handled");
+        (answered, output)
+    }
+
+    #[test]
+    fn a_reset_mid_headers_is_a_quiet_disconnect() {
+        let config = Config::default();
+        let err =
+            read_request(&mut ResetAfter::new("GET / HTTP/1.1\r\nHost: loc"), &config).unwrap_err();
+        assert!(is_disconnect(&err));
+        assert_eq!(answer_to(err), (false, Vec::new()));
+
+        // So is a client that hangs up before the end of the head.
+        let err =
+            read_request(&mut Duplex::new("GET / HTTP/1.1\r\nHost: loc"), &config).unwrap_err();
+        assert_eq!(answer_to(err), (false, Vec::new()));
+
+        // Whereas a malformed head is worth a response.
+        let err =
+            read_request(&mut Duplex::new("GET / HTTP/1.1\r\nHost\r\n\r\n"), &config).unwrap_err();
+        let (answered, output) = answer_to(err);
+        assert!(answered);
+        assert_eq!(Wire::parse(&output).status, 400);
+    }
+
+    #[test]
+    fn access_lines_have_the_status_and_size_sent() {
+        assert_eq!(
+            access_line(
+                "127.0.0.1:50000",
+                "GET",
+                "/echo/abc",
+                200,
+                3,
+                Duration::from_micros(400)
+            ),
+            "127.0.0.1:50000 GET /echo/abc 200 3 0ms"
+        );
+        assert_eq!(
+            access_line(
+                "127.0.0.1:50000",
+                "GET",
+                "/echo/a\nb",
+                404,
+                9,
+                Duration::from_millis(12)
+            ),
+            "127.0.0.1:50000 GET /echo/a\\nb 404 9 12ms"
+        );
+    }
+
+    #[test]
+    fn handler_errors_map_to_the_closest_status() {
+        let failure = |kind: io::ErrorKind| {
+            anyhow::Error::new(io::Error::from(kind)).context("Failed to write file")
+        };
+        assert_eq!(
+            handler_error_status(&failure(io::ErrorKind::PermissionDenied)),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            handler_error_status(&failure(io::ErrorKind::NotFound)),
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            handler_error_status(&failure(io::ErrorKind::Other)),
+            StatusCode::InternalServerError
+        );
+        assert_eq!(
+            handler_error_status(&anyhow::anyhow!("no io::Error at all")),
+            StatusCode::InternalServerError
+        );
+    }
+}
@@ -0,0 +1,924 @@
+//! Serving files from the `--directory`.
+
+use crate::compression::{
+    compress_reader, on_the_fly, preferred_encoding, worth_compressing, Encoding,
+};
+use crate::config::{Config, EtagMode};
+use crate::http::date::{http_date, parse_http_date, unix_seconds};
+use crate::http::request::Request;
+use crate::http::response::{error_response, not_acceptable, Body, Response, CHUNK_SIZE};
+use crate::http::*;
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const EARLY_HINTS_SUFFIX: &str = ".early-hints";
+
+pub(crate) const GZIP_SUFFIX: &str = ".gz";
+
+/// How much of a file `--fadvise` asks the kernel to start reading up front.
+#[cfg(target_os = "linux")]
+pub(crate) const FADVISE_WILLNEED_BYTES: libc::off_t = 8 * 1024 * 1024;
+
+pub(crate) fn serve_file(
+    filepath: PathBuf,
+    request: &Request,
+    config: &Config,
+) -> Result<Response> {
+    let headers = &request.headers;
+    let mut file = File::open(&filepath)?;
+    if config.fadvise {
+        advise_sequential(&file);
+    }
+    let metadata = file.metadata().context("Failed to read file metadata")?;
+    if !metadata.is_file() {
+        return Ok(error_response(StatusCode::NotFound));
+    }
+
+    // A sidecar is compressed already, so it's worth sending whatever the
+    // file is, and even when nothing is compressed on the fly.
+    let sidecar = config
+        .precompressed
+        .then(|| gzip_sidecar(&filepath, &config.directory))
+        .flatten();
+    let negotiable = sidecar.is_some()
+        || worth_compressing(Some(content_type_for(&filepath)), Some(metadata.len()));
+    let negotiated = match preferred_encoding(request) {
+        None => return Ok(not_acceptable()),
+        Some(Encoding::Gzip) if sidecar.is_some() => Encoding::Gzip,
+        Some(_) if !negotiable => Encoding::Identity,
+        Some(encoding) => on_the_fly(encoding, request, config),
+    };
+
+    // A compressed body is a different representation, so it needs its own
+    // tag. The sidecar's bytes aren't those of compressing on the fly, so
+    // its tag comes from the sidecar itself.
+    let file_tag = file_etag(&filepath, &metadata, config.etag_mode);
+    let sidecar_tag = sidecar.as_deref().and_then(|sidecar| {
+        let metadata = fs::metadata(sidecar).ok()?;
+        file_etag(sidecar, &metadata, config.etag_mode)
+    });
+    let etag_for = |encoding: Encoding| {
+        let tag = match encoding {
+            Encoding::Gzip if sidecar.is_some() => sidecar_tag.as_ref(),
+            _ => file_tag.as_ref(),
+        };
+        tag.map(|etag| match encoding.token() {
+            Some(token) => format!("\"{}-{}\"", etag, token),
+            None => format!("\"{}\"", etag),
+        })
+    };
+    let modified = metadata.modified().ok().and_then(unix_seconds);
+
+    // If-None-Match is the more precise of the two, so If-Modified-Since
+    // only counts when it is absent.
+    let etag = etag_for(negotiated);
+    let not_modified = match headers.get("If-None-Match") {
+        Some(tags) => etag_matches(tags, etag.as_deref()),
+        None => match (
+            modified,
+            headers.get("If-Modified-Since").and_then(parse_http_date),
+        ) {
+            (Some(modified), Some(since)) => modified <= since,
+            _ => false,
+        },
+    };
+
+    // The client already has this exact representation, so skip reading and
+    // compressing the file altogether.
+    let cache_control = config.cache_control_for(content_type_for(&filepath));
+    if not_modified {
+        let mut response = Response::new(StatusCode::NotModified);
+        response.headers = validators(etag, modified);
+        if let Some(value) = cache_control {
+            response.set_header("Cache-Control", value);
+        }
+        vary(&mut response, sidecar.is_some(), negotiable, config);
+        return Ok(response);
+    }
+
+    // Ranges are offsets into the stored bytes, so a ranged response is
+    // never compressed. A failed If-Range means the client's partial copy is
+    // stale and it should get the whole file instead.
+    let len = metadata.len();
+    let if_range_holds = match headers.get("If-Range") {
+        Some(validator) => {
+            if_range_matches(validator, etag_for(Encoding::Identity).as_deref(), modified)
+        }
+        None => true,
+    };
+    let range = match headers.get("Range") {
+        Some(range) if if_range_holds => parse_range(range, len),
+        _ => ByteRange::Full,
+    };
+    let encoding = match range {
+        ByteRange::Full => negotiated,
+        _ => Encoding::Identity,
+    };
+
+    let mut response = Response::ok()
+        .content_type(content_type_for(&filepath))
+        .header("Accept-Ranges", "bytes");
+    response
+        .headers
+        .extend(validators(etag_for(encoding), modified));
+    if let Some(value) = cache_control {
+        response.set_header("Cache-Control", value);
+    }
+    vary(&mut response, sidecar.is_some(), negotiable, config);
+
+    if config.early_hints {
+        response.early_hints = read_early_hints(&filepath, &config.directory);
+    }
+
+    // The file is streamed rather than read into memory. Compressing on the
+    // fly means the final length isn't known, so that goes out chunked.
+    match range {
+        ByteRange::Full => {}
+        ByteRange::Partial(start, end) => {
+            file.seek(SeekFrom::Start(start))?;
+            let length = end - start + 1;
+            response.status = StatusCode::PartialContent;
+            response.headers.push((
+                "Content-Range".to_string(),
+                format!("bytes {}-{}/{}", start, end, len),
+            ));
+            response.body = throttle(
+                Body::Reader(Box::new(file.take(length)), Some(length)),
+                request,
+                config,
+            );
+            return Ok(response);
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = error_response(StatusCode::RangeNotSatisfiable);
+            response
+                .headers
+                .push(("Content-Range".to_string(), format!("bytes */{}", len)));
+            return Ok(response);
+        }
+    }
+
+    if let Some(token) = encoding.token() {
+        response
+            .headers
+            .push(("Content-Encoding".to_string(), token.to_string()));
+    }
+    response.body = match (encoding, sidecar) {
+        // Sent as it is stored, so its length is known up front, for a HEAD
+        // as much as a GET.
+        (Encoding::Gzip, Some(sidecar)) => {
+            let file = File::open(sidecar)?;
+            let len = file
+                .metadata()
+                .context("Failed to read sidecar metadata")?
+                .len();
+            Body::Reader(Box::new(file.take(len)), Some(len))
+        }
+        (Encoding::Identity, _) => Body::Reader(Box::new(file.take(len)), Some(len)),
+        (encoding, _) => Body::Reader(compress_reader(file, encoding, config)?, None),
+    };
+    response.body = throttle(response.body, request, config);
+
+    Ok(response)
+}
+
+/// Marks a file response as depending on `Accept-Encoding` when it does:
+/// always with a sidecar to choose, otherwise when the file is `negotiable`
+/// and could be compressed on the fly.
+fn vary(response: &mut Response, sidecar: bool, negotiable: bool, config: &Config) {
+    if sidecar {
+        response.add_vary("Accept-Encoding");
+    } else if negotiable {
+        response.vary_on_encoding(config);
+    }
+}
+
+/// The `.gz` sidecar next to `filepath`, if there is one.
+pub(crate) fn gzip_sidecar(filepath: &Path, directory: &str) -> Option<PathBuf> {
+    sidecar(filepath, GZIP_SUFFIX, directory).filter(|sidecar| sidecar.is_file())
+}
+
+/// `filepath` with `suffix` appended, resolved the way `resolve_path`
+/// resolves the file itself, so a sidecar that is a symlink out of
+/// `directory` doesn't count.
+fn sidecar(filepath: &Path, suffix: &str, directory: &str) -> Option<PathBuf> {
+    let mut sidecar = filepath.as_os_str().to_owned();
+    sidecar.push(suffix);
+    let base = Path::new(directory).canonicalize().ok()?;
+    let resolved = Path::new(&sidecar).canonicalize().ok()?;
+    resolved.starts_with(&base).then_some(resolved)
+}
+
+/// Paces a streamed body to the `--download-rate`, if there is one. The
+/// rate is per client IP: every download to the same address draws on one
+/// token bucket, so parallel connections or ranged requests share the rate
+/// rather than each getting all of it. A request with no known peer gets a
+/// bucket of its own.
+pub(crate) fn throttle(body: Body, request: &Request, config: &Config) -> Body {
+    match (body, config.download_rate) {
+        (Body::Reader(reader, length), Some(rate)) => Body::Reader(
+            Box::new(Throttled::new(reader, rate, bucket_for(request.peer))),
+            length,
+        ),
+        (body, _) => body,
+    }
+}
+
+/// The bucket shared by the downloads in progress to `peer`.
+fn bucket_for(peer: Option<IpAddr>) -> Arc<Mutex<Bucket>> {
+    static BUCKETS: OnceLock<Mutex<HashMap<IpAddr, Arc<Mutex<Bucket>>>>> = OnceLock::new();
+    let Some(peer) = peer else {
+        return Arc::default();
+    };
+    let mut buckets = BUCKETS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    // Clients with no download in progress are forgotten.
+    buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+    Arc::clone(buckets.entry(peer).or_default())
+}
+
+/// A token bucket that starts empty and holds at most one chunk, so the
+/// rate holds even over short stretches.
+pub(crate) struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        let capacity = rate.min(CHUNK_SIZE as u64) as f64;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(capacity);
+        self.refilled = now;
+    }
+}
+
+/// A reader limited to `rate` bytes per second by a token bucket, which may
+/// be shared with other readers.
+pub(crate) struct Throttled<R> {
+    inner: R,
+    rate: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl<R> Throttled<R> {
+    pub(crate) fn new(inner: R, rate: u64, bucket: Arc<Mutex<Bucket>>) -> Self {
+        Throttled {
+            inner,
+            rate,
+            bucket,
+        }
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // The tokens are taken before reading, and any left unused given
+        // back after, so readers sharing the bucket can't overdraw it.
+        let allowed = loop {
+            let wait = {
+                let mut bucket = self.bucket();
+                bucket.refill(self.rate);
+                if bucket.tokens >= 1.0 {
+                    let allowed = buf.len().min(bucket.tokens as usize);
+                    bucket.tokens -= allowed as f64;
+                    break allowed;
+                }
+                (1.0 - bucket.tokens) / self.rate as f64
+            };
+            thread::sleep(Duration::from_secs_f64(wait));
+        };
+        let result = self.inner.read(&mut buf[..allowed]);
+        let used = *result.as_ref().unwrap_or(&0);
+        self.bucket().tokens += (allowed - used) as f64;
+        result
+    }
+}
+
+/// Whether an `If-None-Match` list names `etag`. `*` matches any existing
+/// file, and a `W/` prefix is ignored since the comparison is weak.
+pub(crate) fn etag_matches(tags: &str, etag: Option<&str>) -> bool {
+    if tags.trim() == "*" {
+        return true;
+    }
+    let Some(etag) = etag else {
+        return false;
+    };
+    fn opaque(tag: &str) -> &str {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag)
+    }
+    tags.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// The `ETag` and `Last-Modified` headers for a file, where known.
+pub(crate) fn validators(etag: Option<String>, modified: Option<u64>) -> Vec<(String, String)> {
+    let mut headers = Vec::new();
+    if let Some(etag) = etag {
+        headers.push(("ETag".to_string(), etag));
+    }
+    if let Some(modified) = modified {
+        headers.push(("Last-Modified".to_string(), http_date(modified)));
+    }
+    headers
+}
+
+/// What part of a file a `Range` header asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// No usable range, so the whole file.
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes. Multiple
+/// ranges, other units and malformed values are ignored, which serves the
+/// whole file.
+pub(crate) fn parse_range(value: &str, len: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let parse = |n: &str| -> Option<u64> {
+        if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+            None
+        } else {
+            n.parse().ok()
+        }
+    };
+
+    match (parse(start), parse(end)) {
+        // bytes=-N, the last N bytes.
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(len.saturating_sub(suffix), len - 1)
+            }
+        }
+        // bytes=N-, from N to the end.
+        (Some(start), None) if end.is_empty() => {
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(start, len - 1)
+            }
+        }
+        (Some(start), Some(end)) if start <= end => {
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(start, end.min(len - 1))
+            }
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+/// An `If-Range` validator holds only if it exactly matches the current
+/// file: a strong ETag compared byte for byte, or an HTTP-date equal to its
+/// Last-Modified. Weak ETags never match.
+pub(crate) fn if_range_matches(validator: &str, etag: Option<&str>, modified: Option<u64>) -> bool {
+    if validator.starts_with('"') {
+        etag == Some(validator)
+    } else if validator.starts_with("W/") {
+        false
+    } else {
+        match (parse_http_date(validator), modified) {
+            (Some(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+}
+
+/// Guesses a MIME type from the file extension, falling back to
+/// `application/octet-stream`.
+pub(crate) fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("gz") => "application/gzip",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Reads the `Link` values for a file's early hints from a sidecar next to
+/// it, e.g. `index.html.early-hints`, one per line.
+pub(crate) fn read_early_hints(filepath: &Path, directory: &str) -> Vec<String> {
+    let Some(contents) = sidecar(filepath, EARLY_HINTS_SUFFIX, directory)
+        .and_then(|sidecar| fs::read_to_string(sidecar).ok())
+    else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Returns the (unquoted) ETag for a file, or `None` if the information it
+/// is derived from isn't available.
+pub(crate) fn file_etag(path: &Path, metadata: &Metadata, mode: EtagMode) -> Option<String> {
+    match mode {
+        EtagMode::Mtime => {
+            let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+            Some(format!("{:x}-{:x}", metadata.len(), modified.as_nanos()))
+        }
+        EtagMode::Content => content_etag(path, metadata),
+    }
+}
+
+pub(crate) struct CachedEtag {
+    len: u64,
+    modified: SystemTime,
+    etag: String,
+}
+
+/// Hashes the file's contents, reusing the previous hash for as long as its
+/// size and modification time stay the same.
+pub(crate) fn content_etag(path: &Path, metadata: &Metadata) -> Option<String> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, CachedEtag>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    let modified = metadata.modified().ok();
+    if let (Some(modified), Some(cached)) = (modified, cache.lock().ok()?.get(path)) {
+        if cached.len == metadata.len() && cached.modified == modified {
+            return Some(cached.etag.clone());
+        }
+    }
+
+    let etag = format!("{:016x}", fnv1a(File::open(path).ok()?).ok()?);
+    // Without an mtime there is no way to notice a change, so don't cache.
+    if let Some(modified) = modified {
+        cache.lock().ok()?.insert(
+            path.to_path_buf(),
+            CachedEtag {
+                len: metadata.len(),
+                modified,
+                etag: etag.clone(),
+            },
+        );
+    }
+    Some(etag)
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is guaranteed not to
+/// change between Rust releases, which keeps content ETags stable.
+pub(crate) fn fnv1a<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &byte in &buf[..n] {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Tells the kernel `file` is about to be read front to back so it can read
+/// ahead more aggressively.
+#[cfg(target_os = "linux")]
+pub(crate) fn advise_sequential(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is a valid descriptor for as long as `file` is borrowed,
+    // and posix_fadvise doesn't touch any memory of ours.
+    let results = unsafe {
+        [
+            libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL),
+            libc::posix_fadvise(fd, 0, FADVISE_WILLNEED_BYTES, libc::POSIX_FADV_WILLNEED),
+        ]
+    };
+    for result in results {
+        if result != 0 {
+            eprintln!(
+                "posix_fadvise failed: {}",
+                io::Error::from_raw_os_error(result)
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn advise_sequential(_file: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{respond, TempDir};
+    use flate2::read::GzDecoder;
+    use pretty_assertions::assert_eq;
+
+    fn serving(dir: &TempDir) -> Config {
+        Config {
+            directory: dir.directory(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn if_range_etags_must_match_strongly() {
+        let etag = Some("\"abc\"");
+        assert!(if_range_matches("\"abc\"", etag, None));
+        assert!(!if_range_matches("\"abd\"", etag, None));
+        assert!(!if_range_matches("W/\"abc\"", etag, None));
+        assert!(!if_range_matches("\"abc\"", None, None));
+    }
+
+    #[test]
+    fn if_range_dates_must_match_exactly() {
+        let modified = 784_111_777;
+        let date = http_date(modified);
+        assert!(if_range_matches(&date, Some("\"abc\""), Some(modified)));
+        assert!(!if_range_matches(
+            &date,
+            Some("\"abc\""),
+            Some(modified + 1)
+        ));
+        assert!(!if_range_matches(&date, Some("\"abc\""), None));
+        assert!(!if_range_matches("yesterday", None, Some(modified)));
+    }
+
+    #[test]
+    fn a_stale_if_range_serves_the_whole_file() {
+        let dir = TempDir::new();
+        dir.write("data.bin", "0123456789");
+        let full = respond("GET /files/data.bin HTTP/1.1\r\n\r\n", serving(&dir));
+        let etag = full.header("ETag").expect("ETag");
+        let modified = full.header("Last-Modified").expect("Last-Modified");
+        let ranged = |validator: &str| {
+            let request = format!(
+                "GET /files/data.bin HTTP/1.1\r\nRange: bytes=2-4\r\nIf-Range: {}\r\n\r\n",
+                validator
+            );
+            respond(&request, serving(&dir))
+        };
+
+        for validator in [etag, modified] {
+            let wire = ranged(validator);
+            assert_eq!(wire.status, 206);
+            assert_eq!(wire.body, b"234");
+        }
+        for validator in ["\"stale\"", "Sun, 06 Nov 1994 08:49:37 GMT"] {
+            let wire = ranged(validator);
+            assert_eq!(wire.status, 200);
+            assert_eq!(wire.body, b"0123456789");
+        }
+    }
+
+    #[test]
+    fn files_larger_than_a_chunk_arrive_intact() {
+        let dir = TempDir::new();
+        let binary: Vec<u8> = (0..=255).cycle().take(CHUNK_SIZE * 3 + 17).collect();
+        dir.write("big.bin", &binary);
+        let text = "line after line\n".repeat(CHUNK_SIZE / 4);
+        dir.write("big.txt", &text);
+
+        let wire = respond("GET /files/big.bin HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(
+            wire.header("Content-Length"),
+            Some(&*binary.len().to_string())
+        );
+        assert!(wire.body == binary, "body differs from the file");
+
+        // Compressed on the fly, and over HTTP/1.0 so it isn't chunked.
+        let wire = respond(
+            "GET /files/big.txt HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n",
+            serving(&dir),
+        );
+        assert_eq!(wire.header("Content-Encoding"), Some("gzip"));
+        let mut decoded = String::new();
+        GzDecoder::new(&wire.body[..])
+            .read_to_string(&mut decoded)
+            .expect("valid gzip");
+        assert!(decoded == text, "body differs from the file");
+    }
+
+    #[test]
+    fn etags_follow_the_chosen_mode() {
+        let dir = TempDir::new();
+        let a = dir.write("a.txt", "same bytes");
+        let b = dir.write("b.txt", "same bytes");
+        let long_ago = UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&b)
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+        let etag = |path: &Path, mode| file_etag(path, &fs::metadata(path).unwrap(), mode);
+
+        // Identical contents share a tag, however they got there.
+        let content = etag(&a, EtagMode::Content).expect("content tag");
+        assert_eq!(etag(&b, EtagMode::Content), Some(content.clone()));
+        // Size and mtime are cheaper, but differ for the same bytes.
+        assert_ne!(etag(&a, EtagMode::Mtime), etag(&b, EtagMode::Mtime));
+
+        fs::write(&a, "other bytes").unwrap();
+        assert_ne!(etag(&a, EtagMode::Content), Some(content));
+    }
+
+    #[test]
+    fn content_types_follow_the_extension() {
+        let dir = TempDir::new();
+        dir.write("index.html", "<p>hi</p>");
+        dir.write("README", "plain");
+
+        let wire = respond("GET /files/index.html HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(
+            wire.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let wire = respond("GET /files/README HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(
+            wire.header("Content-Type"),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn extensions_match_whatever_their_case() {
+        for (name, expected) in [
+            ("Photo.JPG", "image/jpeg"),
+            ("photo.jpeg", "image/jpeg"),
+            ("Style.CSS", "text/css; charset=utf-8"),
+            ("data.json", "application/json"),
+            ("notes.txt", "text/plain; charset=utf-8"),
+            ("icon.svg", "image/svg+xml"),
+            ("archive.tar.xz", "application/octet-stream"),
+            ("Makefile", "application/octet-stream"),
+            (".hidden", "application/octet-stream"),
+        ] {
+            assert_eq!(content_type_for(Path::new(name)), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_replayed_etag_is_not_modified() {
+        let dir = TempDir::new();
+        dir.write("cached.txt", "cache me");
+
+        let first = respond("GET /files/cached.txt HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(first.status, 200);
+        let etag = first.header("ETag").expect("ETag");
+
+        let request = format!(
+            "GET /files/cached.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n",
+            etag
+        );
+        let replayed = respond(&request, serving(&dir));
+        assert_eq!(replayed.status, 304);
+        assert_eq!(replayed.header("ETag"), Some(etag));
+        assert_eq!(replayed.header("Content-Length"), None);
+        assert!(replayed.body.is_empty());
+
+        let other = respond(
+            "GET /files/cached.txt HTTP/1.1\r\nIf-None-Match: \"other\"\r\n\r\n",
+            serving(&dir),
+        );
+        assert_eq!(other.status, 200);
+        assert_eq!(other.body, b"cache me");
+    }
+
+    #[test]
+    fn if_modified_since_compares_against_the_mtime() {
+        let dir = TempDir::new();
+        dir.write("dated.txt", "dated");
+        let since = |date: &str| {
+            let request = format!(
+                "GET /files/dated.txt HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n",
+                date
+            );
+            respond(&request, serving(&dir))
+        };
+        let first = respond("GET /files/dated.txt HTTP/1.1\r\n\r\n", serving(&dir));
+        let modified = first.header("Last-Modified").expect("Last-Modified");
+        let secs = parse_http_date(modified).expect("an HTTP-date");
+
+        let unchanged = since(modified);
+        assert_eq!(unchanged.status, 304);
+        assert!(unchanged.body.is_empty());
+        assert_eq!(since(&http_date(secs + 3600)).status, 304);
+
+        let changed = since(&http_date(secs - 1));
+        assert_eq!(changed.status, 200);
+        assert_eq!(changed.body, b"dated");
+        assert_eq!(since("last tuesday").status, 200);
+    }
+
+    #[test]
+    fn ranges_are_resolved_against_the_length() {
+        use ByteRange::*;
+        for (value, expected) in [
+            ("bytes=0-99", Partial(0, 99)),
+            ("bytes=0-0", Partial(0, 0)),
+            ("bytes=-100", Partial(900, 999)),
+            ("bytes=-5000", Partial(0, 999)),
+            ("bytes=500-", Partial(500, 999)),
+            ("bytes=990-5000", Partial(990, 999)),
+            ("bytes=1000-", Unsatisfiable),
+            ("bytes=1000-1999", Unsatisfiable),
+            ("bytes=-0", Unsatisfiable),
+            ("bytes=5-1", Full),
+            ("bytes=0-1,5-9", Full),
+            ("items=0-9", Full),
+        ] {
+            assert_eq!(parse_range(value, 1000), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn ranged_responses_carry_the_stored_bytes() {
+        let dir = TempDir::new();
+        dir.write("letters.txt", "abcdefghijklmnopqrstuvwxyz".repeat(10));
+        let ranged = |range: &str| {
+            let request = format!(
+                "GET /files/letters.txt HTTP/1.1\r\nRange: {}\r\nAccept-Encoding: gzip\r\n\r\n",
+                range
+            );
+            respond(&request, serving(&dir))
+        };
+
+        let first = ranged("bytes=0-4");
+        assert_eq!(first.status, 206);
+        assert_eq!(first.header("Content-Range"), Some("bytes 0-4/260"));
+        assert_eq!(first.header("Content-Encoding"), None);
+        assert_eq!(first.body, b"abcde");
+
+        let suffix = ranged("bytes=-3");
+        assert_eq!(suffix.header("Content-Range"), Some("bytes 257-259/260"));
+        assert_eq!(suffix.body, b"xyz");
+
+        let open = ranged("bytes=250-");
+        assert_eq!(open.body, b"qrstuvwxyz");
+
+        let beyond = ranged("bytes=300-400");
+        assert_eq!(beyond.status, 416);
+        assert_eq!(beyond.header("Content-Range"), Some("bytes */260"));
+    }
+
+    #[test]
+    fn a_changed_file_invalidates_its_etag() {
+        let dir = TempDir::new();
+        dir.write("live.txt", "version one");
+        let get = |if_none_match: &str| {
+            let request = format!(
+                "GET /files/live.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n",
+                if_none_match
+            );
+            respond(&request, serving(&dir))
+        };
+        let first = respond("GET /files/live.txt HTTP/1.1\r\n\r\n", serving(&dir));
+        let etag = first.header("ETag").expect("ETag").to_string();
+
+        assert_eq!(get(&etag).status, 304);
+        assert_eq!(get(&format!("\"nope\", W/{}", etag)).status, 304);
+        assert_eq!(get("*").status, 304);
+
+        dir.write("live.txt", "version two!");
+        let fresh = get(&etag);
+        assert_eq!(fresh.status, 200);
+        assert_eq!(fresh.body, b"version two!");
+        assert_ne!(fresh.header("ETag"), Some(etag.as_str()));
+    }
+
+    #[test]
+    fn cache_control_follows_the_content_type() {
+        let dir = TempDir::new();
+        dir.write("logo.png", [0x89, b'P', b'N', b'G']);
+        dir.write("page.html", "<p>hi</p>");
+        let config = || Config {
+            cache_control: vec![("image/*".into(), "public, max-age=604800".into())],
+            ..serving(&dir)
+        };
+
+        let image = respond("GET /files/logo.png HTTP/1.1\r\n\r\n", config());
+        assert_eq!(
+            image.header("Cache-Control"),
+            Some("public, max-age=604800")
+        );
+        let page = respond("GET /files/page.html HTTP/1.1\r\n\r\n", config());
+        assert_eq!(page.header("Cache-Control"), None);
+    }
+
+    #[test]
+    fn sidecars_linking_out_of_the_directory_are_ignored() {
+        let outer = TempDir::new();
+        let secret = outer.write("secret.gz", "keep out");
+        let hints = outer.write("secret-hints", "</secret.css>; rel=preload");
+        let page = outer.write("served/page.txt", "all work and no play ".repeat(20));
+        let served = page.parent().expect("served directory");
+        std::os::unix::fs::symlink(&secret, served.join("page.txt.gz")).unwrap();
+        std::os::unix::fs::symlink(&hints, served.join("page.txt.early-hints")).unwrap();
+        let directory = served.to_string_lossy().into_owned();
+
+        assert_eq!(gzip_sidecar(&page, &directory), None);
+        assert!(read_early_hints(&page, &directory).is_empty());
+
+        let wire = respond(
+            "GET /files/page.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+            Config {
+                directory,
+                precompressed: true,
+                ..Config::default()
+            },
+        );
+        assert_eq!(wire.status, 200);
+        let mut body = String::new();
+        GzDecoder::new(&wire.dechunk()[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "all work and no play ".repeat(20));
+    }
+
+    #[test]
+    fn downloads_to_one_client_share_a_bucket() {
+        let ip = |last: u8| Some(IpAddr::from([192, 0, 2, last]));
+        let first = bucket_for(ip(1));
+        assert!(Arc::ptr_eq(&first, &bucket_for(ip(1))));
+        assert!(!Arc::ptr_eq(&first, &bucket_for(ip(2))));
+        assert!(!Arc::ptr_eq(&bucket_for(None), &bucket_for(None)));
+
+        // Once no download holds it, the client is forgotten.
+        drop(first);
+        let _ = bucket_for(ip(3));
+        assert_eq!(Arc::strong_count(&bucket_for(ip(1))), 2);
+    }
+
+    #[test]
+    fn readers_sharing_a_bucket_split_the_rate() {
+        const RATE: u64 = 20_000;
+        let bucket = Arc::<Mutex<Bucket>>::default();
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let bucket = Arc::clone(&bucket);
+                scope.spawn(move || {
+                    let mut reader = Throttled::new(io::repeat(b'a').take(RATE / 2), RATE, bucket);
+                    io::copy(&mut reader, &mut io::sink()).unwrap();
+                });
+            }
+        });
+        // Each alone would be done in half a second.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    }
+}
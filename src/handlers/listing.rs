@@ -0,0 +1,388 @@
+//! HTML and JSON listings of directories under `/files/`.
+
+use crate::compression::parse_qvalue;
+use crate::config::Config;
+use crate::escape::{html_escape, json_escape, percent_encode};
+use crate::http::date::{http_date, unix_seconds};
+use crate::http::request::{Headers, Request};
+use crate::http::response::Response;
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+/// The page for `--enable-listing`, unless `--listing-template` replaces it.
+/// The part between `{{#entries}}` and `{{/entries}}` is repeated for every
+/// entry.
+pub(crate) const DEFAULT_LISTING_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>Index of {{path}}</title></head>
+<body>
+<h1>Index of {{path}}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Modified</th></tr>
+{{#entries}}<tr><td><a href=\"{{href}}\">{{name}}</a></td><td>{{size}}</td><td>{{modified}}</td></tr>
+{{/entries}}</table>
+</body>
+</html>
+";
+
+pub(crate) struct ListingEntry {
+    name: String,
+    /// Percent-encoded link to the entry under `/files/`.
+    href: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<u64>,
+}
+
+/// Renders an index of `dir`, which has already been resolved inside the
+/// served directory, as HTML or, if the client asks for it, JSON. Entries
+/// that resolve outside of it, through symlinks, are left out.
+pub(crate) fn serve_listing(dir: &Path, request: &Request, config: &Config) -> Result<Response> {
+    let base = Path::new(&config.directory)
+        .canonicalize()
+        .context("Failed to resolve directory")?;
+    let relative = dir
+        .strip_prefix(&base)
+        .context("Listing outside directory")?;
+    let mut prefix = String::from("/files/");
+    for part in relative.components() {
+        prefix.push_str(&percent_encode(&part.as_os_str().to_string_lossy()));
+        prefix.push('/');
+    }
+
+    let mut entries = Vec::new();
+    for item in fs::read_dir(dir)?.flatten() {
+        let Ok(resolved) = item.path().canonicalize() else {
+            continue;
+        };
+        let Ok(metadata) = fs::metadata(&resolved) else {
+            continue;
+        };
+        if !resolved.starts_with(&base) {
+            continue;
+        }
+        let name = item.file_name().to_string_lossy().into_owned();
+        let mut href = format!("{}{}", prefix, percent_encode(&name));
+        if metadata.is_dir() {
+            href.push('/');
+        }
+        entries.push(ListingEntry {
+            name,
+            href,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok().and_then(unix_seconds),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    if prefers_json(&request.headers) {
+        let items: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"name\":\"{}\",\"size\":{},\"is_dir\":{}}}",
+                    json_escape(&entry.name),
+                    entry.size,
+                    entry.is_dir
+                )
+            })
+            .collect();
+        let json = format!("[{}]", items.join(","));
+        return Response::ok()
+            .content_type("application/json")
+            .body(json.as_bytes())
+            .with_encoding(request, config);
+    }
+
+    let template = config
+        .listing_template
+        .as_deref()
+        .unwrap_or(DEFAULT_LISTING_TEMPLATE);
+    let html = render_listing(template, &prefix, &entries);
+    Response::ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html.as_bytes())
+        .with_encoding(request, config)
+}
+
+/// Whether the client's `Accept` ranks JSON above HTML, for listings.
+pub(crate) fn prefers_json(headers: &Headers) -> bool {
+    let Some(accept) = headers.get("accept") else {
+        return false;
+    };
+    let quality = |media: &str| {
+        accept
+            .split(',')
+            .find_map(|entry| {
+                let mut params = entry.split(';');
+                if !params.next()?.trim().eq_ignore_ascii_case(media) {
+                    return None;
+                }
+                Some(
+                    params
+                        .filter_map(|param| param.split_once('='))
+                        .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                        .map_or(Some(1.0), |(_, value)| parse_qvalue(value))
+                        .unwrap_or(0.0),
+                )
+            })
+            .unwrap_or(0.0)
+    };
+    quality("application/json") > quality("text/html")
+}
+
+/// Fails unless `template` has the placeholders `render_listing` fills in.
+pub fn check_listing_template(template: &str) -> Result<()> {
+    for placeholder in ["{{path}}", "{{#entries}}", "{{/entries}}"] {
+        if !template.contains(placeholder) {
+            anyhow::bail!("missing {}", placeholder);
+        }
+    }
+    match (template.find("{{#entries}}"), template.find("{{/entries}}")) {
+        (Some(start), Some(end)) if start < end => Ok(()),
+        _ => anyhow::bail!("{{{{/entries}}}} comes before {{{{#entries}}}}"),
+    }
+}
+
+/// Fills in a listing template: `{{path}}` anywhere, and `{{name}}`,
+/// `{{href}}`, `{{size}}` and `{{modified}}` once per entry inside the
+/// `{{#entries}}` section. Every value is HTML-escaped.
+pub(crate) fn render_listing(template: &str, path: &str, entries: &[ListingEntry]) -> String {
+    let path = html_escape(path);
+    let (head, rest) = template
+        .split_once("{{#entries}}")
+        .unwrap_or((template, "{{/entries}}"));
+    let (row, tail) = rest.split_once("{{/entries}}").unwrap_or((rest, ""));
+
+    let mut html = String::new();
+    fill(&mut html, head, &[("path", &path)]);
+    for entry in entries {
+        let name = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map_or_else(|| "-".to_string(), http_date);
+        fill(
+            &mut html,
+            row,
+            &[
+                ("path", &path),
+                ("name", &html_escape(&name)),
+                ("href", &html_escape(&entry.href)),
+                ("size", &size),
+                ("modified", &html_escape(&modified)),
+            ],
+        );
+    }
+    fill(&mut html, tail, &[("path", &path)]);
+    html
+}
+
+/// Appends `template` to `html` with each `{{key}}` in `values` replaced.
+/// This is done in one pass over the template, so a value that looks like a
+/// placeholder, say a file called `{{size}}`, is left as it is.
+fn fill(html: &mut String, template: &str, values: &[(&str, &str)]) {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest[2..].split_once("}}").and_then(|(key, _)| {
+            values
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| (key.len() + 4, *value))
+        });
+        match value {
+            Some((len, value)) => {
+                html.push_str(value);
+                rest = &rest[len..];
+            }
+            None => {
+                html.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    html.push_str(rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{respond, TempDir};
+    use pretty_assertions::assert_eq;
+
+    fn listing(dir: &TempDir) -> Config {
+        Config {
+            directory: dir.directory(),
+            enable_listing: true,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn templates_need_every_placeholder_in_order() {
+        assert!(check_listing_template(DEFAULT_LISTING_TEMPLATE).is_ok());
+        assert!(check_listing_template("{{path}}{{#entries}}{{name}}{{/entries}}").is_ok());
+
+        let missing = check_listing_template("{{#entries}}{{name}}{{/entries}}").unwrap_err();
+        assert_eq!(missing.to_string(), "missing {{path}}");
+        assert!(check_listing_template("{{path}}{{#entries}}{{name}}").is_err());
+        assert!(check_listing_template("{{path}}{{/entries}}{{#entries}}").is_err());
+    }
+
+    #[test]
+    fn a_custom_template_fills_in_each_entry() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        dir.write("b.txt", "bb");
+        let config = Config {
+            listing_template: Some("{{path}}:{{#entries}}[{{name}} {{size}}]{{/entries}}".into()),
+            ..listing(&dir)
+        };
+
+        let wire = respond("GET /files/ HTTP/1.1\r\n\r\n", config);
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.body, b"/files/:[a.txt 1][b.txt 2]");
+    }
+
+    #[test]
+    fn names_that_look_like_placeholders_are_left_as_they_are() {
+        let dir = TempDir::new();
+        dir.write("{{href}}", "a");
+        dir.write("{{size}}", "bb");
+        let config = Config {
+            listing_template: Some(
+                "{{path}}:{{#entries}}[{{name}} {{size}} {{other}}]{{/entries}}".into(),
+            ),
+            ..listing(&dir)
+        };
+
+        let wire = respond("GET /files/ HTTP/1.1\r\n\r\n", config);
+        assert_eq!(
+            String::from_utf8(wire.body).unwrap(),
+            "/files/:[{{href}} 1 {{other}}][{{size}} 2 {{other}}]"
+        );
+    }
+
+    #[test]
+    fn entry_names_are_escaped() {
+        let dir = TempDir::new();
+        dir.write("<img src=x onerror=alert(1)>.txt", "gotcha");
+
+        let wire = respond("GET /files/ HTTP/1.1\r\n\r\n", listing(&dir));
+        let html = String::from_utf8(wire.body).expect("UTF-8 listing");
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(
+            html.contains("&lt;img src=x onerror=alert(1)&gt;.txt"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("href=\"/files/%3Cimg%20src%3Dx%20onerror%3Dalert%281%29%3E.txt\""),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn listings_link_files_and_directories() {
+        let dir = TempDir::new();
+        dir.write("b.txt", "bb");
+        dir.write("a.txt", "a");
+        dir.write("nested/deep.txt", "deep");
+
+        let wire = respond("GET /files/ HTTP/1.1\r\n\r\n", listing(&dir));
+        assert_eq!(wire.status, 200);
+        assert_eq!(
+            wire.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let html = String::from_utf8(wire.body).expect("UTF-8 listing");
+        let links: Vec<&str> = html
+            .split("href=\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .collect();
+        // Directories first, then files by name.
+        assert_eq!(links, ["/files/nested/", "/files/a.txt", "/files/b.txt"]);
+
+        let wire = respond("GET /files/nested/ HTTP/1.1\r\n\r\n", listing(&dir));
+        let html = String::from_utf8(wire.body).expect("UTF-8 listing");
+        assert!(
+            html.contains("<title>Index of /files/nested/</title>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("href=\"/files/nested/deep.txt\""), "{}", html);
+    }
+
+    #[test]
+    fn directories_are_forbidden_without_listings() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a");
+        let config = Config {
+            directory: dir.directory(),
+            ..Config::default()
+        };
+
+        let wire = respond("GET /files/ HTTP/1.1\r\n\r\n", config);
+        assert_eq!(wire.status, 403);
+    }
+
+    #[test]
+    fn listings_come_as_json_when_preferred() {
+        let dir = TempDir::new();
+        dir.write("full/b.txt", "bb");
+        dir.write("full/sub/c.txt", "c");
+        std::fs::create_dir(std::path::Path::new(&dir.directory()).join("empty")).unwrap();
+        let json = |path: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nAccept: application/json\r\n\r\n", path);
+            let wire = respond(&request, listing(&dir));
+            assert_eq!(wire.status, 200);
+            assert_eq!(wire.header("Content-Type"), Some("application/json"));
+            String::from_utf8(wire.body).expect("UTF-8 listing")
+        };
+
+        // A directory's size depends on the file system.
+        let full = json("/files/full/");
+        assert!(full.starts_with(r#"[{"name":"sub","size":"#), "{}", full);
+        assert!(
+            full.ends_with(r#","is_dir":true},{"name":"b.txt","size":2,"is_dir":false}]"#),
+            "{}",
+            full
+        );
+        assert_eq!(json("/files/empty/"), "[]");
+    }
+
+    #[test]
+    fn listings_leave_out_links_outside_the_directory() {
+        let outer = TempDir::new();
+        let secret = outer.write("secret.txt", "keep out");
+        let inside = outer.write("served/inside.txt", "in");
+        let served = inside.parent().expect("served directory");
+        std::os::unix::fs::symlink(&secret, served.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&inside, served.join("alias.txt")).unwrap();
+        let config = Config {
+            directory: served.to_string_lossy().into_owned(),
+            enable_listing: true,
+            ..Config::default()
+        };
+
+        let wire = respond(
+            "GET /files/ HTTP/1.1\r\nAccept: application/json\r\n\r\n",
+            config,
+        );
+        assert_eq!(
+            String::from_utf8(wire.body).unwrap(),
+            r#"[{"name":"alias.txt","size":2,"is_dir":false},{"name":"inside.txt","size":2,"is_dir":false}]"#
+        );
+    }
+}
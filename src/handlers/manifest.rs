@@ -0,0 +1,364 @@
+//! `/manifest.json`, the sizes and SHA-256 hashes of every served file.
+
+use crate::config::Config;
+use crate::escape::json_escape;
+use crate::http::request::Request;
+use crate::http::response::{Response, CHUNK_SIZE};
+use anyhow::{Context, Result};
+use ring::digest::{Context as Digest, SHA256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The most files `/manifest.json` lists. Anything beyond is left out and
+/// the manifest is marked as truncated.
+pub(crate) const MANIFEST_MAX_FILES: usize = 10_000;
+
+/// The most directories walked for `/manifest.json`, so a tree of empty
+/// directories can't make the walk go on forever either.
+pub(crate) const MANIFEST_MAX_DIRS: usize = 10_000;
+
+/// How long a manifest is served before the tree is checked for changes
+/// again, so a burst of requests walks it only once.
+pub(crate) const MANIFEST_RECHECK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+struct ManifestEntry {
+    /// Relative to the served directory, as it would appear after `/files/`.
+    path: String,
+    size: u64,
+    modified: Option<SystemTime>,
+    /// Hex-encoded SHA-256 of the contents.
+    hash: String,
+}
+
+/// A rendered manifest, and the entries it was rendered from.
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+    truncated: bool,
+    body: String,
+    checked: Instant,
+}
+
+/// Builds the manifest for `directory` ahead of the first request for it,
+/// returning how many files it lists.
+pub fn build_manifest(directory: &str) -> Result<usize> {
+    Ok(cached_manifest(directory, Duration::ZERO)?.entries.len())
+}
+
+/// The manifest for `directory`, as last built if that was less than
+/// `recheck` ago. Otherwise the tree is walked again, and only files whose
+/// size or modification time changed are hashed again.
+fn cached_manifest(directory: &str, recheck: Duration) -> Result<Arc<Manifest>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, Arc<Manifest>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    let base = Path::new(directory)
+        .canonicalize()
+        .context("Failed to resolve directory")?;
+    // Held across the walk, so concurrent requests wait for the one walk
+    // instead of each doing their own.
+    let mut cache = cache
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Manifest cache poisoned"))?;
+    let previous = cache.get(&base);
+    if let Some(previous) = previous.filter(|previous| previous.checked.elapsed() < recheck) {
+        return Ok(Arc::clone(previous));
+    }
+
+    let (entries, truncated) = walk(
+        &base,
+        previous.map_or(&[][..], |previous| &previous.entries),
+        MANIFEST_MAX_FILES,
+        MANIFEST_MAX_DIRS,
+    );
+    let body = match previous {
+        Some(previous) if previous.entries == entries && previous.truncated == truncated => {
+            previous.body.clone()
+        }
+        _ => render(&entries, truncated),
+    };
+    let manifest = Arc::new(Manifest {
+        entries,
+        truncated,
+        body,
+        checked: Instant::now(),
+    });
+    cache.insert(base, Arc::clone(&manifest));
+    Ok(manifest)
+}
+
+/// Walks `base` and hashes every regular file in it, returning the entries
+/// sorted by path and whether `max_files` or `max_dirs` cut the walk short.
+/// A file whose size and modification time match its entry in `previous`
+/// keeps that hash rather than being read again.
+fn walk(
+    base: &Path,
+    previous: &[ManifestEntry],
+    max_files: usize,
+    max_dirs: usize,
+) -> (Vec<ManifestEntry>, bool) {
+    let previous: HashMap<&str, &ManifestEntry> = previous
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let mut entries = Vec::new();
+    let mut pending = vec![base.to_path_buf()];
+    let mut walked = 0;
+    let mut truncated = false;
+
+    while let Some(dir) = pending.pop() {
+        if walked == max_dirs {
+            truncated = true;
+            break;
+        }
+        walked += 1;
+        let listing = match fs::read_dir(&dir) {
+            Ok(listing) => listing,
+            Err(e) => {
+                eprintln!("Skipping {} in manifest: {}", dir.display(), e);
+                continue;
+            }
+        };
+        for item in listing.flatten() {
+            let path = item.path();
+            // Symlinks are skipped rather than followed, so the walk can
+            // neither loop nor leave the directory.
+            let Ok(metadata) = fs::symlink_metadata(&path) else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+            if entries.len() == max_files {
+                truncated = true;
+                break;
+            }
+            let Ok(relative) = path.strip_prefix(base) else {
+                continue;
+            };
+            let relative: Vec<_> = relative
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect();
+            let relative = relative.join("/");
+            let size = metadata.len();
+            let modified = metadata.modified().ok();
+            // Without an mtime there is no way to notice a change, so the
+            // file is hashed every time.
+            let hash = match previous.get(relative.as_str()) {
+                Some(entry)
+                    if modified.is_some() && (entry.size, entry.modified) == (size, modified) =>
+                {
+                    entry.hash.clone()
+                }
+                _ => match File::open(&path).and_then(sha256) {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        eprintln!("Skipping {} in manifest: {}", path.display(), e);
+                        continue;
+                    }
+                },
+            };
+            entries.push(ManifestEntry {
+                path: relative,
+                size,
+                modified,
+                hash,
+            });
+        }
+        if truncated {
+            break;
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    (entries, truncated)
+}
+
+/// The hex-encoded SHA-256 of everything `reader` yields.
+fn sha256<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut digest = Digest::new(&SHA256);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => digest.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(digest
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn render(entries: &[ManifestEntry], truncated: bool) -> String {
+    let files: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"path\":\"{}\",\"size\":{},\"hash\":\"sha256:{}\"}}",
+                json_escape(&entry.path),
+                entry.size,
+                entry.hash
+            )
+        })
+        .collect();
+    format!(
+        "{{\"files\":[{}],\"truncated\":{}}}",
+        files.join(","),
+        truncated
+    )
+}
+
+pub(crate) fn serve_manifest(request: &Request, config: &Config) -> Result<Response> {
+    let manifest = cached_manifest(&config.directory, MANIFEST_RECHECK)?;
+    Response::ok()
+        .content_type("application/json")
+        .body(manifest.body.as_bytes())
+        .with_encoding(request, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{respond, TempDir};
+    use pretty_assertions::assert_eq;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn paths(entries: &[ManifestEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.path.as_str()).collect()
+    }
+
+    #[test]
+    fn the_manifest_lists_sha256_hashes() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "abc");
+        dir.write("nested/b.txt", "");
+        let wire = respond(
+            "GET /manifest.json HTTP/1.1\r\n\r\n",
+            Config {
+                directory: dir.directory(),
+                manifest: true,
+                ..Config::default()
+            },
+        );
+        assert_eq!(wire.status, 200);
+        assert_eq!(
+            String::from_utf8(wire.body).unwrap(),
+            format!(
+                "{{\"files\":[{{\"path\":\"a.txt\",\"size\":3,\"hash\":\"sha256:{}\"}},\
+                 {{\"path\":\"nested/b.txt\",\"size\":0,\"hash\":\"sha256:{}\"}}],\"truncated\":false}}",
+                ABC_SHA256, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+            )
+        );
+    }
+
+    #[test]
+    fn symlinks_are_skipped() {
+        let outer = TempDir::new();
+        let secret = outer.write("secret.txt", "keep out");
+        let inside = outer.write("served/inside.txt", "abc");
+        let served = inside.parent().expect("served directory");
+        std::os::unix::fs::symlink(&secret, served.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(
+            outer.write("other/x.txt", "x").parent().unwrap(),
+            served.join("loop"),
+        )
+        .unwrap();
+
+        let (entries, truncated) = walk(&served.canonicalize().unwrap(), &[], 10, 10);
+        assert_eq!(paths(&entries), ["inside.txt"]);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn the_walk_stops_at_the_limits() {
+        let dir = TempDir::new();
+        for name in ["a", "b", "c"] {
+            dir.write(name, name);
+        }
+        let base = Path::new(&dir.directory()).canonicalize().unwrap();
+        let (entries, truncated) = walk(&base, &[], 2, 10);
+        assert_eq!(entries.len(), 2);
+        assert!(truncated);
+        assert!(!walk(&base, &[], 3, 10).1);
+
+        let dir = TempDir::new();
+        dir.write("one/two/three/file", "deep");
+        let base = Path::new(&dir.directory()).canonicalize().unwrap();
+        let (entries, truncated) = walk(&base, &[], 10, 3);
+        assert!(entries.is_empty());
+        assert!(truncated);
+        assert_eq!(paths(&walk(&base, &[], 10, 4).0), ["one/two/three/file"]);
+    }
+
+    #[test]
+    fn only_changed_files_are_hashed_again() {
+        let dir = TempDir::new();
+        let kept = dir.write("kept.txt", "abc");
+        let changed = dir.write("changed.txt", "abc");
+        let base = Path::new(&dir.directory()).canonicalize().unwrap();
+        let (before, _) = walk(&base, &[], 10, 10);
+
+        // Same size and mtime: the old hash stands without reading the file.
+        let mtime = fs::metadata(&kept).unwrap().modified().unwrap();
+        fs::write(&kept, "xyz").unwrap();
+        File::options()
+            .write(true)
+            .open(&kept)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        // A new mtime means the file is read again.
+        fs::write(&changed, "xyz").unwrap();
+        File::options()
+            .write(true)
+            .open(&changed)
+            .unwrap()
+            .set_modified(mtime + Duration::from_secs(1))
+            .unwrap();
+
+        let (after, _) = walk(&base, &before, 10, 10);
+        let hash = |entries: &[ManifestEntry], path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.path == path)
+                .unwrap()
+                .hash
+                .clone()
+        };
+        assert_eq!(hash(&after, "kept.txt"), ABC_SHA256);
+        assert_ne!(hash(&after, "changed.txt"), ABC_SHA256);
+    }
+
+    #[test]
+    fn a_manifest_is_reused_until_it_is_rechecked() {
+        let dir = TempDir::new();
+        let file = dir.write("a.txt", "abc");
+        let first = cached_manifest(&dir.directory(), Duration::ZERO).unwrap();
+        let again = cached_manifest(&dir.directory(), Duration::from_secs(60)).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        fs::write(&file, "abcd").unwrap();
+        let stale = cached_manifest(&dir.directory(), Duration::from_secs(60)).unwrap();
+        assert_eq!(stale.body, first.body);
+        let fresh = cached_manifest(&dir.directory(), Duration::ZERO).unwrap();
+        assert_ne!(fresh.body, first.body);
+        assert!(fresh.body.contains("\"size\":4"), "{}", fresh.body);
+    }
+}
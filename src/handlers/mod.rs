@@ -0,0 +1,1001 @@
+//! Routing requests to the code that answers them.
+
+mod files;
+mod listing;
+mod manifest;
+
+pub use listing::check_listing_template;
+pub use manifest::build_manifest;
+
+use crate::compression::{compress_reader, on_the_fly, preferred_encoding};
+use crate::config::Config;
+use crate::connection::handler_error_status;
+use crate::escape::{json_escape, percent_encode};
+use crate::http::request::Request;
+use crate::http::response::{error_response, not_acceptable, Body, Response, CHUNK_SIZE};
+use crate::http::*;
+use crate::router::Router;
+use anyhow::{Context, Result};
+use files::serve_file;
+use listing::{prefers_json, serve_listing};
+use manifest::serve_manifest;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Every method the built-in routes handle, allowed for CORS requests.
+pub(crate) const ALL_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS"];
+
+/// The most times `?repeat=N` repeats an echo, however large N is.
+pub(crate) const MAX_ECHO_REPEAT: usize = 100;
+
+/// Stores the body of a POST or PUT to `/files/<name>`. POST only creates
+/// files unless the client sends `X-Overwrite: true`, PUT always replaces
+/// them. With `X-Append: true` the body is added to the end of the file
+/// instead. A new file gets a 201, an existing one a 200.
+pub(crate) fn handle_upload(request: &Request, config: &Config) -> Result<Response> {
+    let filepath = match upload_target(request, config) {
+        Ok(filepath) => filepath,
+        Err(rejection) => return Ok(rejection),
+    };
+    let existed = filepath.exists();
+    if flag(request, "X-Append") {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&filepath)?
+            .write_all(&request.body)
+            .context("Failed to append to file")?;
+    } else {
+        write_replacing(&filepath, &request.body)?;
+    }
+
+    Ok(Response::new(if existed {
+        StatusCode::Ok
+    } else {
+        StatusCode::Created
+    }))
+}
+
+/// Where an upload goes, or the response turning it down. This only needs
+/// the head of the request, so it is also checked before the body is read.
+pub(crate) fn upload_target(request: &Request, config: &Config) -> Result<PathBuf, Response> {
+    let filename = &request.path["/files/".len()..];
+    let Some(filepath) = resolve_path(&config.directory, filename, false) else {
+        return Err(error_response(StatusCode::NotFound));
+    };
+    let creates_only =
+        request.method == "POST" && !flag(request, "X-Overwrite") && !flag(request, "X-Append");
+    if filepath.is_dir() || (creates_only && filepath.exists()) {
+        return Err(error_response(StatusCode::Conflict));
+    }
+    Ok(filepath)
+}
+
+/// Whether the header `name` is set to `true`.
+fn flag(request: &Request, name: &str) -> bool {
+    request
+        .headers
+        .get(name)
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into
+/// place, so a failed write never leaves a truncated file behind.
+pub(crate) fn write_replacing(path: &Path, contents: &[u8]) -> Result<()> {
+    static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let written = File::create(&temp)
+        .and_then(|mut file| file.write_all(contents))
+        .and_then(|_| fs::rename(&temp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e).context("Failed to write file");
+    }
+    Ok(())
+}
+
+/// A 405 for a method `request.path` doesn't support, listing the
+/// `methods` it does. The `Allow` header is what clients should go by, the
+/// body just spells it out, as JSON if the client prefers that.
+pub(crate) fn method_not_allowed(request: &Request, methods: &[&str]) -> Response {
+    let response = Response::new(StatusCode::MethodNotAllowed).header("Allow", &methods.join(", "));
+    if prefers_json(&request.headers) {
+        let allowed: Vec<String> = methods
+            .iter()
+            .map(|method| format!("\"{}\"", method))
+            .collect();
+        response.content_type("application/json").body(format!(
+            "{{\"error\":\"{}\",\"method\":\"{}\",\"allowed\":[{}]}}",
+            StatusCode::MethodNotAllowed.reason(),
+            json_escape(&request.method),
+            allowed.join(",")
+        ))
+    } else {
+        response.content_type("text/plain").body(format!(
+            "{}\n{} is not allowed here. Allowed: {}\n",
+            StatusCode::MethodNotAllowed,
+            request.method,
+            methods.join(", ")
+        ))
+    }
+}
+
+/// Answers `OPTIONS` with the methods the target supports. With `--cors`
+/// this doubles as the response to a preflight.
+pub(crate) fn handle_options(methods: &[&str], config: &Config) -> Response {
+    let methods = methods.join(", ");
+    let response = Response::new(StatusCode::NoContent).header("Allow", &methods);
+    match config.cors_origin {
+        // Uploads send a Content-Type, and with `--auth-file` credentials.
+        Some(_) => response
+            .header("Access-Control-Allow-Methods", &methods)
+            .header(
+                "Access-Control-Allow-Headers",
+                "Content-Type, Authorization",
+            ),
+        None => response,
+    }
+}
+
+pub(crate) fn handle_delete(request: &Request, config: &Config) -> Result<Response> {
+    let filename = &request.path["/files/".len()..];
+    let Some(filepath) = resolve_path(&config.directory, filename, true) else {
+        return Ok(error_response(StatusCode::NotFound));
+    };
+
+    // Only files can be deleted; removing directories is out of scope.
+    if filepath.is_dir() {
+        return Ok(error_response(StatusCode::Conflict));
+    }
+
+    fs::remove_file(filepath).context("Failed to delete file")?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// Registers the built-in routes, which answer with `config`.
+pub(crate) fn register_default_routes(router: &mut Router, config: &Arc<Config>) {
+    type RouteHandler = fn(&Request, &Config) -> Result<Response>;
+    let handle = |handler: RouteHandler| {
+        let config = Arc::clone(config);
+        move |request: &Request| {
+            handler(request, &config).unwrap_or_else(|e| {
+                eprintln!(
+                    "Error handling {} {}: {:#}",
+                    request.method, request.path, e
+                );
+                error_response(handler_error_status(&e))
+            })
+        }
+    };
+    let check_upload = || {
+        let config = Arc::clone(config);
+        move |request: &Request| upload_target(request, &config).err()
+    };
+
+    router.get("/", handle(serve_root));
+    router.get("/files/*", handle(handle_get_file));
+    router
+        .post("/files/*", handle(handle_upload))
+        .check_before_body(check_upload());
+    router
+        .put("/files/*", handle(handle_upload))
+        .check_before_body(check_upload());
+    router.delete("/files/*", handle(handle_delete));
+    router.get("/echo/*", handle(serve_echo));
+    router.get("/user-agent", handle(serve_user_agent));
+    router.get("/health", handle(serve_health));
+    router.get("/healthz", handle(serve_health));
+    router.get("/readyz", handle(serve_ready));
+    router.get("/stats", handle(serve_stats));
+    if config.manifest {
+        router.get("/manifest.json", handle(serve_manifest));
+    }
+}
+
+pub(crate) fn handle_get_file(request: &Request, config: &Config) -> Result<Response> {
+    let path = request.path.as_str();
+    let filename = &path["/files/".len()..];
+    let filepath = resolve_path(&config.directory, filename, true)
+        .or_else(|| clean_url_fallback(filename, config));
+    let Some(filepath) = filepath else {
+        return Ok(error_response(StatusCode::NotFound));
+    };
+    if !filepath.is_dir() {
+        serve_file(filepath, request, config)
+    } else if !path.ends_with('/') {
+        // Relative links in the index only resolve against the slash form.
+        Ok(redirect_to_dir(path))
+    } else if let Some(index) = find_index(&filepath, config) {
+        serve_file(index, request, config)
+    } else if config.enable_listing {
+        serve_listing(&filepath, request, config)
+    } else {
+        Ok(error_response(StatusCode::Forbidden))
+    }
+}
+
+pub(crate) fn serve_root(request: &Request, config: &Config) -> Result<Response> {
+    match find_index(Path::new(&config.directory), config) {
+        Some(index) => serve_file(index, request, config),
+        None => Ok(Response::new(StatusCode::Ok)),
+    }
+}
+
+pub(crate) fn serve_health(request: &Request, config: &Config) -> Result<Response> {
+    Response::ok()
+        .content_type("text/plain")
+        .body(b"ok")
+        .with_encoding(request, config)
+}
+
+/// Not ready once shutting down, so load balancers stop sending traffic
+/// while the connections drain.
+pub(crate) fn serve_ready(request: &Request, config: &Config) -> Result<Response> {
+    if config.shutdown.load(Ordering::SeqCst) {
+        return Ok(error_response(StatusCode::ServiceUnavailable));
+    }
+    Response::ok()
+        .content_type("text/plain")
+        .body(b"ready")
+        .with_encoding(request, config)
+}
+
+pub(crate) fn serve_stats(request: &Request, config: &Config) -> Result<Response> {
+    Response::ok()
+        .content_type("application/json")
+        .body(config.stats.to_json())
+        .with_encoding(request, config)
+}
+
+/// A 301 to `path` with a trailing slash.
+pub(crate) fn redirect_to_dir(path: &str) -> Response {
+    let segments: Vec<String> = path.split('/').map(percent_encode).collect();
+    Response::new(StatusCode::MovedPermanently)
+        .header("Location", &format!("{}/", segments.join("/")))
+}
+
+/// The first of the `--index` files inside `dir`, if there is one that stays
+/// within the served directory.
+pub(crate) fn find_index(dir: &Path, config: &Config) -> Option<PathBuf> {
+    let base = Path::new(&config.directory).canonicalize().ok()?;
+    config.index_files.iter().find_map(|name| {
+        let index = dir.join(name).canonicalize().ok()?;
+        (index.starts_with(&base) && index.is_file()).then_some(index)
+    })
+}
+
+/// With `--static-site`, `/files/docs/about` serves `docs/about.html` when
+/// there is nothing called `about`.
+pub(crate) fn clean_url_fallback(filename: &str, config: &Config) -> Option<PathBuf> {
+    let has_extension = Path::new(filename).extension().is_some();
+    if !config.static_site || has_extension || filename.is_empty() || filename.ends_with('/') {
+        return None;
+    }
+    resolve_path(&config.directory, &format!("{}.html", filename), true)
+        .filter(|path| path.is_file())
+}
+
+/// Liveness and readiness probes, which keep answering during maintenance.
+pub(crate) fn is_health_check(path: &str) -> bool {
+    matches!(path, "/health" | "/healthz" | "/readyz")
+}
+
+pub(crate) fn maintenance_response(config: &Config) -> Response {
+    let mut response = match &config.maintenance_page {
+        Some(page) => Response::new(StatusCode::ServiceUnavailable)
+            .content_type("text/html; charset=utf-8")
+            .body(page.as_bytes()),
+        None => error_response(StatusCode::ServiceUnavailable),
+    };
+    response
+        .headers
+        .push(("Retry-After".to_string(), config.retry_after.to_string()));
+    response
+}
+
+/// Resolves `filename` inside `directory`, returning `None` if the result
+/// would escape it. Unless `must_exist` is set, only the parent directory has
+/// to exist so that new files can be created.
+pub(crate) fn resolve_path(directory: &str, filename: &str, must_exist: bool) -> Option<PathBuf> {
+    let base = Path::new(directory).canonicalize().ok()?;
+    let joined = base.join(filename);
+    let resolved = match joined.canonicalize() {
+        Ok(path) => path,
+        Err(_) if !must_exist => {
+            let name = joined.file_name()?;
+            joined.parent()?.canonicalize().ok()?.join(name)
+        }
+        Err(_) => return None,
+    };
+    resolved.starts_with(&base).then_some(resolved)
+}
+
+pub(crate) fn serve_user_agent(request: &Request, config: &Config) -> Result<Response> {
+    let user_agent = request.headers.get("user-agent").unwrap_or_default();
+    Response::ok()
+        .content_type("text/plain")
+        .body(user_agent.as_bytes())
+        .with_encoding(request, config)
+}
+
+/// Echoes the rest of the path, `?repeat=N` times over.
+pub(crate) fn serve_echo(request: &Request, config: &Config) -> Result<Response> {
+    let echo_str = &request.path[6..];
+    let repeat = match request.query.get("repeat").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) => n.min(MAX_ECHO_REPEAT),
+        Some(Err(_)) => return Ok(error_response(StatusCode::BadRequest)),
+        None => 1,
+    };
+    let len = echo_str.len() * repeat;
+    if len < CHUNK_SIZE {
+        return Response::ok()
+            .content_type("text/plain")
+            .body(echo_str.repeat(repeat))
+            .with_encoding(request, config);
+    }
+
+    // Too large to be worth building in memory, so it's generated as it is
+    // sent, and compressed on the way out into a chunked body.
+    let Some(encoding) = preferred_encoding(request) else {
+        return Ok(not_acceptable());
+    };
+    let encoding = on_the_fly(encoding, request, config);
+    let echo = Repeated::new(echo_str.as_bytes().to_vec(), repeat);
+    let mut response = Response::ok().content_type("text/plain");
+    response.vary_on_encoding(config);
+    response.body = match encoding.token() {
+        Some(token) => {
+            response.set_header("Content-Encoding", token);
+            Body::Reader(compress_reader(echo, encoding, config)?, None)
+        }
+        None => Body::Reader(Box::new(echo), Some(len as u64)),
+    };
+    Ok(response)
+}
+
+/// `bytes` read `times` over, without holding more than one copy.
+pub(crate) struct Repeated {
+    bytes: Vec<u8>,
+    times: usize,
+    offset: usize,
+}
+
+impl Repeated {
+    pub(crate) fn new(bytes: Vec<u8>, times: usize) -> Self {
+        Repeated {
+            bytes,
+            times,
+            offset: 0,
+        }
+    }
+}
+
+impl Read for Repeated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() && self.times > 0 && !self.bytes.is_empty() {
+            let n = (buf.len() - filled).min(self.bytes.len() - self.offset);
+            buf[filled..filled + n].copy_from_slice(&self.bytes[self.offset..self.offset + n]);
+            filled += n;
+            self.offset += n;
+            if self.offset == self.bytes.len() {
+                self.offset = 0;
+                self.times -= 1;
+            }
+        }
+        Ok(filled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::MIN_COMPRESS_SIZE;
+    use crate::testing::{respond, TempDir};
+    use pretty_assertions::assert_eq;
+
+    fn serving(dir: &TempDir) -> Config {
+        Config {
+            directory: dir.directory(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn user_agent_is_echoed_back() {
+        let wire = respond(
+            "GET /user-agent HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n",
+            Config::default(),
+        );
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.header("Content-Type"), Some("text/plain"));
+        assert_eq!(wire.body, b"curl/8.0");
+    }
+
+    #[test]
+    fn files_are_served_from_the_directory() {
+        let dir = TempDir::new();
+        dir.write("notes.txt", "some notes");
+
+        let wire = respond("GET /files/notes.txt HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.header("Content-Length"), Some("10"));
+        assert_eq!(wire.body, b"some notes");
+    }
+
+    #[test]
+    fn echo_repeats_only_when_asked() {
+        let echo = |target: &str| {
+            let wire = respond(
+                &format!("GET {} HTTP/1.1\r\n\r\n", target),
+                Config::default(),
+            );
+            (wire.status, String::from_utf8(wire.body).unwrap())
+        };
+
+        assert_eq!(echo("/echo/abc"), (200, "abc".to_string()));
+        assert_eq!(echo("/echo/abc?colour=blue"), (200, "abc".to_string()));
+        assert_eq!(echo("/echo/abc?repeat=3"), (200, "abcabcabc".to_string()));
+        assert_eq!(echo("/echo/a?repeat=1000000").1.len(), MAX_ECHO_REPEAT);
+        assert_eq!(echo("/echo/abc?repeat=lots").0, 400);
+    }
+
+    #[test]
+    fn only_echoes_worth_it_are_compressed() {
+        let echo = |text: &str| {
+            let request = format!(
+                "GET /echo/{} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+                text
+            );
+            respond(&request, Config::default())
+        };
+
+        let short = echo("tiny");
+        assert_eq!(short.header("Content-Encoding"), None);
+        assert_eq!(short.header("Content-Length"), Some("4"));
+        assert_eq!(short.body, b"tiny");
+
+        let long = "abcdefgh".repeat(MIN_COMPRESS_SIZE as usize);
+        let large = echo(&long);
+        assert_eq!(large.header("Content-Encoding"), Some("gzip"));
+        assert!(large.body.len() < long.len());
+    }
+
+    #[test]
+    fn large_echoes_are_streamed() {
+        let segment = "0123456789".repeat(100);
+        let expected = segment.repeat(MAX_ECHO_REPEAT);
+        assert!(expected.len() > CHUNK_SIZE);
+        let echo = |encoding: &str| {
+            let request = format!(
+                "GET /echo/{}?repeat={} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                segment, MAX_ECHO_REPEAT, encoding
+            );
+            respond(&request, Config::default())
+        };
+
+        let plain = echo("identity");
+        assert_eq!(
+            plain.header("Content-Length"),
+            Some(&*expected.len().to_string())
+        );
+        assert!(plain.body == expected.as_bytes());
+
+        let gzipped = echo("gzip");
+        assert_eq!(gzipped.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(gzipped.header("Content-Length"), None);
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzipped.dechunk()[..])
+            .read_to_end(&mut decoded)
+            .expect("valid gzip");
+        assert!(decoded == expected.as_bytes());
+    }
+
+    #[test]
+    fn escaped_file_names_are_served() {
+        let dir = TempDir::new();
+        dir.write("my notes.txt", "spaced out");
+
+        let wire = respond("GET /files/my%20notes.txt HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.body, b"spaced out");
+    }
+
+    #[test]
+    fn a_chunked_upload_is_stored_dechunked() {
+        let dir = TempDir::new();
+        let wire = respond(
+            "POST /files/x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n7;note=ext\r\n, world\r\n0\r\nX-Trailer: ignored\r\n\r\n",
+            serving(&dir),
+        );
+        assert_eq!(wire.status, 201);
+        let stored = std::fs::read(Path::new(&dir.directory()).join("x")).unwrap();
+        assert_eq!(stored, b"hello, world");
+    }
+
+    #[test]
+    fn uploads_create_refuse_overwrite_and_append() {
+        let dir = TempDir::new();
+        let upload = |method: &str, extra: &str, body: &str| {
+            let request = format!(
+                "{} /files/log.txt HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                method,
+                extra,
+                body.len(),
+                body
+            );
+            respond(&request, serving(&dir)).status
+        };
+        let stored =
+            || std::fs::read_to_string(Path::new(&dir.directory()).join("log.txt")).unwrap();
+
+        assert_eq!(upload("POST", "", "one\n"), 201);
+        assert_eq!(upload("POST", "", "clobber\n"), 409);
+        assert_eq!(stored(), "one\n");
+
+        assert_eq!(upload("PUT", "", "two\n"), 200);
+        assert_eq!(stored(), "two\n");
+        assert_eq!(upload("POST", "X-Overwrite: true\r\n", "three\n"), 200);
+        assert_eq!(stored(), "three\n");
+
+        assert_eq!(upload("POST", "X-Append: true\r\n", "four\n"), 200);
+        assert_eq!(stored(), "three\nfour\n");
+
+        // Nothing is left behind from writing through a temporary file.
+        let names: Vec<_> = std::fs::read_dir(dir.directory())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["log.txt"]);
+    }
+
+    #[test]
+    fn put_creates_then_updates() {
+        let dir = TempDir::new();
+        let put = |body: &str| {
+            let request = format!(
+                "PUT /files/doc.txt HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            respond(&request, serving(&dir)).status
+        };
+
+        assert_eq!(put("first"), 201);
+        assert_eq!(put("second"), 200);
+        let stored = std::fs::read(Path::new(&dir.directory()).join("doc.txt")).unwrap();
+        assert_eq!(stored, b"second");
+
+        let escape = respond(
+            "PUT /files/../escape.txt HTTP/1.1\r\nContent-Length: 1\r\n\r\nx",
+            serving(&dir),
+        );
+        assert_eq!(escape.status, 404);
+    }
+
+    #[test]
+    fn paths_outside_the_directory_are_not_found() {
+        let outer = TempDir::new();
+        outer.write("secret.txt", "keep out");
+        let inner = outer.write("served/sub/dir/file.txt", "nested");
+        let served = inner.ancestors().nth(3).expect("served directory");
+        let config = || Config {
+            directory: served.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let secret = outer.directory() + "/secret.txt";
+
+        for target in [
+            "/files/../secret.txt",
+            "/files/sub/../../secret.txt",
+            "/files/%2e%2e/secret.txt",
+            "/files/%2E%2E%2Fsecret.txt",
+            &format!("/files/{}", secret),
+        ] {
+            let wire = respond(&format!("GET {} HTTP/1.1\r\n\r\n", target), config());
+            assert_eq!(wire.status, 404, "{}", target);
+        }
+
+        let wire = respond("GET /files/sub/dir/file.txt HTTP/1.1\r\n\r\n", config());
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.body, b"nested");
+    }
+
+    #[test]
+    fn delete_removes_a_file_once() {
+        let dir = TempDir::new();
+        let path = dir.write("old.txt", "bye");
+        let delete = || respond("DELETE /files/old.txt HTTP/1.1\r\n\r\n", serving(&dir));
+
+        let wire = delete();
+        assert_eq!(wire.status, 204);
+        assert!(wire.body.is_empty());
+        assert!(!path.exists());
+        assert_eq!(delete().status, 404);
+    }
+
+    /// The bytes the server sent for a compressed echo before echo and
+    /// user-agent responses went through a shared helper, so reworking how
+    /// responses are assembled can't change them unnoticed. Only the `Vary`
+    /// header has been added since.
+    #[test]
+    fn gzip_echo_output_matches_the_original() {
+        let wire = respond(
+            &format!(
+                "GET /echo/{} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+                "abcdefgh".repeat(20)
+            ),
+            Config::default(),
+        );
+        let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(wire.status, 200);
+        assert_eq!(
+            wire.headers,
+            vec![
+                header("Content-Type", "text/plain"),
+                header("Vary", "Accept-Encoding"),
+                header("Content-Encoding", "gzip"),
+                header("Content-Length", "41"),
+            ]
+        );
+        assert_eq!(
+            wire.body,
+            [
+                31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 205, 197, 73, 1, 0, 0, 16, 1, 192, 172, 246, 64,
+                255, 4, 106, 120, 13, 102, 239, 41, 163, 212, 0, 68, 206, 149, 38, 160, 0, 0, 0,
+            ]
+        );
+    }
+
+    /// The same for an echo built with `?repeat=`.
+    #[test]
+    fn gzip_echo_output_is_unchanged() {
+        let wire = respond(
+            "GET /echo/abcdefgh?repeat=40 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+            Config::default(),
+        );
+        let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(wire.status, 200);
+        assert_eq!(
+            wire.headers,
+            vec![
+                header("Content-Type", "text/plain"),
+                header("Vary", "Accept-Encoding"),
+                header("Content-Encoding", "gzip"),
+                header("Content-Length", "42"),
+            ]
+        );
+        assert_eq!(
+            wire.body,
+            [
+                31, 139, 8, 0, 0, 0, 0, 0, 0, 255, 237, 197, 55, 1, 0, 0, 12, 2, 48, 173, 116, 226,
+                95, 1, 30, 184, 185, 130, 234, 217, 123, 34, 90, 10, 79, 38, 194, 151, 64, 1, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn head_sends_the_headers_get_would() {
+        let dir = TempDir::new();
+        dir.write("big.txt", "all work and no play ".repeat(1000));
+        let without_date = |headers: Vec<(String, String)>| {
+            headers
+                .into_iter()
+                .filter(|(name, _)| name != "Date")
+                .collect::<Vec<_>>()
+        };
+
+        for version in ["HTTP/1.0", "HTTP/1.1"] {
+            for encoding in ["identity", "deflate"] {
+                let request = |method: &str| {
+                    format!(
+                        "{} /files/big.txt {}\r\nAccept-Encoding: {}\r\n\r\n",
+                        method, version, encoding
+                    )
+                };
+                let get = respond(&request("GET"), serving(&dir));
+                let head = respond(&request("HEAD"), serving(&dir));
+                assert_eq!(head.status, get.status);
+                assert_eq!(without_date(head.headers), without_date(get.headers));
+                assert!(head.body.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn file_responses_vary_on_accept_encoding() {
+        let dir = TempDir::new();
+        dir.write("page.html", "<p>hello</p>".repeat(50));
+        dir.write("photo.png", [0x89, b'P', b'N', b'G'].repeat(100));
+
+        for accept in ["gzip", "identity"] {
+            let request = format!(
+                "GET /files/page.html HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                accept
+            );
+            let wire = respond(&request, serving(&dir));
+            assert_eq!(wire.header("Vary"), Some("Accept-Encoding"));
+        }
+        let refused = respond(
+            "GET /files/page.html HTTP/1.1\r\nAccept-Encoding: identity;q=0\r\n\r\n",
+            serving(&dir),
+        );
+        assert_eq!(refused.status, 406);
+        assert_eq!(refused.header("Vary"), Some("Accept-Encoding"));
+
+        // Images are never compressed, so they don't vary.
+        let image = respond(
+            "GET /files/photo.png HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+            serving(&dir),
+        );
+        assert_eq!(image.header("Vary"), None);
+    }
+
+    #[test]
+    fn head_describes_the_gzip_sidecar() {
+        let dir = TempDir::new();
+        dir.write("app.js", "console.log('hello');\n".repeat(20));
+        dir.write("app.js.gz", b"\x1f\x8bprecompressed");
+        let request = |method: &str| {
+            format!(
+                "{} /files/app.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+                method
+            )
+        };
+        let precompressed = || Config {
+            precompressed: true,
+            ..serving(&dir)
+        };
+
+        let head = respond(&request("HEAD"), precompressed());
+        assert_eq!(head.status, 200);
+        assert_eq!(head.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(head.header("Content-Length"), Some("15"));
+        assert!(head.body.is_empty());
+
+        let get = respond(&request("GET"), precompressed());
+        assert_eq!(get.body, b"\x1f\x8bprecompressed");
+        assert_eq!(get.header("ETag"), head.header("ETag"));
+
+        // Compressed on the fly, the bytes differ, and so must the tag.
+        let on_the_fly = respond(&request("GET"), serving(&dir));
+        assert_eq!(on_the_fly.header("Content-Encoding"), Some("gzip"));
+        assert_ne!(on_the_fly.header("ETag"), head.header("ETag"));
+    }
+
+    #[test]
+    fn level_0_still_sends_the_gzip_sidecar() {
+        let dir = TempDir::new();
+        dir.write("app.js", "console.log('hello');\n".repeat(20));
+        dir.write("app.js.gz", b"\x1f\x8bprecompressed");
+        let request = "GET /files/app.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let level_0 = |precompressed: bool| Config {
+            compression_level: 0,
+            precompressed,
+            ..serving(&dir)
+        };
+
+        let sidecar = respond(request, level_0(true));
+        assert_eq!(sidecar.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(sidecar.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(sidecar.body, b"\x1f\x8bprecompressed");
+
+        // Without one, nothing is compressed on the fly.
+        let plain = respond(request, level_0(false));
+        assert_eq!(plain.header("Content-Encoding"), None);
+        assert_eq!(plain.body, "console.log('hello');\n".repeat(20).as_bytes());
+    }
+
+    #[test]
+    fn unsupported_methods_are_told_what_the_route_allows() {
+        let wire = respond(
+            "PUT /echo/x HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+            Config::default(),
+        );
+        assert_eq!(wire.status, 405);
+        assert_eq!(wire.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        let length = wire.header("Content-Length").expect("Content-Length");
+        assert_eq!(length, wire.body.len().to_string());
+
+        let wire = respond("PATCH /files/x HTTP/1.1\r\n\r\n", Config::default());
+        assert_eq!(wire.status, 405);
+        assert_eq!(
+            wire.header("Allow"),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn the_root_serves_index_html() {
+        let dir = TempDir::new();
+        let wire = respond("GET / HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(wire.status, 200);
+        assert!(wire.body.is_empty());
+
+        dir.write("index.html", "<h1>home</h1>");
+        let wire = respond("GET / HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(wire.status, 200);
+        assert_eq!(
+            wire.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(wire.body, b"<h1>home</h1>");
+    }
+
+    fn cors() -> Config {
+        Config {
+            cors_origin: Some("https://app.example".to_string()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn a_cors_preflight_allows_the_upload() {
+        let preflight = "OPTIONS /files/x HTTP/1.1\r\nOrigin: https://app.example\r\n\
+                         Access-Control-Request-Method: POST\r\n\r\n";
+        let wire = respond(preflight, cors());
+        assert_eq!(wire.status, 204);
+        assert_eq!(
+            wire.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        let methods = wire
+            .header("Access-Control-Allow-Methods")
+            .expect("methods");
+        assert!(
+            methods.split(", ").any(|method| method == "POST"),
+            "{}",
+            methods
+        );
+        let headers = wire
+            .header("Access-Control-Allow-Headers")
+            .expect("headers");
+        assert!(headers.contains("Content-Type"), "{}", headers);
+
+        // Without --cors it is a plain OPTIONS.
+        let wire = respond(preflight, Config::default());
+        assert_eq!(wire.status, 204);
+        assert!(wire.header("Allow").is_some());
+        assert_eq!(wire.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn a_file_preflight_names_the_file_methods_and_headers() {
+        let wire = respond(
+            "OPTIONS /files/report.pdf HTTP/1.1\r\nOrigin: https://app.example\r\n\
+             Access-Control-Request-Method: DELETE\r\n\
+             Access-Control-Request-Headers: authorization\r\n\r\n",
+            cors(),
+        );
+        assert_eq!(wire.status, 204);
+        assert!(wire.body.is_empty());
+        let methods = "GET, HEAD, POST, PUT, DELETE, OPTIONS";
+        assert_eq!(wire.header("Allow"), Some(methods));
+        assert_eq!(wire.header("Access-Control-Allow-Methods"), Some(methods));
+        assert_eq!(
+            wire.header("Access-Control-Allow-Headers"),
+            Some("Content-Type, Authorization")
+        );
+        assert_eq!(
+            wire.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+    }
+
+    #[test]
+    fn simple_requests_get_cors_headers_too() {
+        let get = "GET /echo/hi HTTP/1.1\r\nOrigin: https://app.example\r\n\r\n";
+        let wire = respond(get, cors());
+        assert_eq!(wire.body, b"hi");
+        assert_eq!(
+            wire.header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert_eq!(
+            wire.header("Access-Control-Allow-Headers"),
+            Some("Content-Type")
+        );
+
+        let wire = respond(get, Config::default());
+        assert_eq!(wire.header("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn options_star_lists_every_method() {
+        let wire = respond("OPTIONS * HTTP/1.1\r\n\r\n", Config::default());
+        assert_eq!(wire.status, 204);
+        assert_eq!(
+            wire.header("Allow"),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn nested_directories_redirect_then_serve_their_index() {
+        let dir = TempDir::new();
+        dir.write("a/b/index.html", "<p>deep</p>");
+
+        let wire = respond("GET /files/a/b HTTP/1.1\r\n\r\n", serving(&dir));
+        assert_eq!(wire.status, 301);
+        let location = wire.header("Location").expect("Location");
+        assert_eq!(location, "/files/a/b/");
+
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", location);
+        let wire = respond(&request, serving(&dir));
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.body, b"<p>deep</p>");
+    }
+
+    #[test]
+    fn static_sites_get_clean_urls() {
+        let dir = TempDir::new();
+        dir.write("docs/index.html", "docs home");
+        dir.write("about.html", "about us");
+        let site = || Config {
+            static_site: true,
+            ..serving(&dir)
+        };
+        let get =
+            |path: &str, config: Config| respond(&format!("GET {} HTTP/1.1\r\n\r\n", path), config);
+
+        let redirect = get("/files/docs", site());
+        assert_eq!(redirect.status, 301);
+        assert_eq!(redirect.header("Location"), Some("/files/docs/"));
+        assert_eq!(get("/files/docs/", site()).body, b"docs home");
+
+        let about = get("/files/about", site());
+        assert_eq!(about.status, 200);
+        assert_eq!(
+            about.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(about.body, b"about us");
+        // The fallback is only for paths without an extension.
+        assert_eq!(get("/files/about.txt", site()).status, 404);
+        assert_eq!(get("/files/about", serving(&dir)).status, 404);
+    }
+
+    #[test]
+    fn index_files_are_tried_in_order() {
+        let dir = TempDir::new();
+        dir.write("site/index.htm", "htm");
+        let get = |config: Config| respond("GET /files/site/ HTTP/1.1\r\n\r\n", config);
+
+        assert_eq!(get(serving(&dir)).body, b"htm");
+        dir.write("site/index.html", "html");
+        assert_eq!(get(serving(&dir)).body, b"html");
+
+        let custom = Config {
+            index_files: vec!["default.html".into(), "index.htm".into()],
+            ..serving(&dir)
+        };
+        assert_eq!(get(custom).body, b"htm");
+        let none = Config {
+            index_files: vec!["default.html".into()],
+            ..serving(&dir)
+        };
+        assert_eq!(get(none).status, 403);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let wire = respond("GET /nowhere HTTP/1.1\r\n\r\n", Config::default());
+        assert_eq!(wire.status, 404);
+    }
+}
@@ -0,0 +1,955 @@
+//! Reading and parsing requests.
+
+use crate::config::{BodyPolicy, Config, DuplicateQuery};
+use crate::escape::log_escape;
+use crate::http::StatusCode;
+use anyhow::{Context, Result};
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+    net::IpAddr,
+    time::Instant,
+};
+use thiserror::Error;
+
+/// The longest request line read, in bytes.
+pub(crate) const MAX_REQUEST_LINE: usize = 8 * 1024;
+/// The most bytes of header lines read for one request.
+pub(crate) const MAX_HEADERS_SIZE: usize = 64 * 1024;
+/// The longest chunk-size line read, extensions included.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Errors caused by the client sending a request we refuse to handle. These
+/// are answered with an error response instead of dropping the connection.
+#[derive(Debug, Error)]
+pub(crate) enum RequestError {
+    #[error("bad request: {0}")]
+    BadRequest(&'static str),
+    #[error("payload too large: {0} bytes")]
+    PayloadTooLarge(usize),
+    #[error("request line over {MAX_REQUEST_LINE} bytes")]
+    UriTooLong,
+    #[error("headers over {MAX_HEADERS_SIZE} bytes")]
+    HeadersTooLarge,
+}
+
+impl RequestError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            RequestError::BadRequest(_) => StatusCode::BadRequest,
+            RequestError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+            RequestError::UriTooLong => StatusCode::UriTooLong,
+            RequestError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+        }
+    }
+}
+
+/// The method and path of a request whose line was read, attached to the
+/// errors after it so they can still be logged against the request.
+#[derive(Debug)]
+pub(crate) struct RequestLine {
+    pub(crate) method: String,
+    pub(crate) path: String,
+}
+
+impl fmt::Display for RequestLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", log_escape(&self.method), log_escape(&self.path))
+    }
+}
+
+/// Request header fields in the order they arrived. Names are matched
+/// case-insensitively, and repeats of a field are joined with commas.
+#[derive(Default)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+/// Headers carrying credentials, left out of anything printed.
+pub(crate) const CREDENTIAL_HEADERS: [&str; 2] = ["authorization", "proxy-authorization"];
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.fields.iter().map(|(name, value)| {
+                let credential = CREDENTIAL_HEADERS
+                    .iter()
+                    .any(|header| name.eq_ignore_ascii_case(header));
+                (name, if credential { "[redacted]" } else { value })
+            }))
+            .finish()
+    }
+}
+
+impl Headers {
+    /// The trimmed value of the field called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn append(&mut self, name: &str, value: &str) {
+        let value = value.trim();
+        match self
+            .fields
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => self.fields.push((name.to_string(), value.to_string())),
+        }
+    }
+}
+
+/// The decoded parameters of a query string, in the order they were given.
+/// Which values of a repeated name are kept is up to `--duplicate-query`.
+#[derive(Debug, Default)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    /// The first kept value of the parameter called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every kept value of the parameter called `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.pairs
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every kept `(name, value)` pair.
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.pairs
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Query,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// The client's address, once the connection has filled it in.
+    pub peer: Option<IpAddr>,
+}
+
+impl Request {
+    /// HTTP/1.1 connections persist unless the client asks to close them,
+    /// HTTP/1.0 ones only if the client asks to keep them open.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers.get("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|option| option.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if has_token("close") {
+            false
+        } else {
+            self.version == "HTTP/1.1" || has_token("keep-alive")
+        }
+    }
+
+    /// Whether the client holds back the body until it gets a `100 Continue`.
+    /// HTTP/1.0 clients can't ask for that.
+    pub(crate) fn expects_continue(&self) -> bool {
+        self.version == "HTTP/1.1"
+            && self
+                .headers
+                .get("expect")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+}
+
+/// Reads the next request on the connection, or `None` if the client closed
+/// it (or went idle) before sending one. The body of a request that expects
+/// `100 Continue` is left unread, for `read_request_body` once the request
+/// is known to be accepted.
+pub(crate) fn read_request<R: BufRead + Write>(
+    reader: &mut R,
+    config: &Config,
+) -> Result<Option<Request>> {
+    let Some(mut request) = parse_request(reader, config)? else {
+        return Ok(None);
+    };
+    if !request.expects_continue() {
+        read_request_body(reader, &mut request, config)?;
+    }
+    Ok(Some(request))
+}
+
+/// Reads the body of `request`, first sending the `100 Continue` the client
+/// may be waiting for. The connection is written to for that.
+pub(crate) fn read_request_body<C: BufRead + Write>(
+    conn: &mut C,
+    request: &mut Request,
+    config: &Config,
+) -> Result<()> {
+    request.body = body_of(conn, request, config).with_context(|| RequestLine {
+        method: request.method.clone(),
+        path: request.path.clone(),
+    })?;
+    Ok(())
+}
+
+fn body_of<C: BufRead + Write>(
+    conn: &mut C,
+    request: &Request,
+    config: &Config,
+) -> Result<Vec<u8>> {
+    let announced =
+        is_chunked(&request.headers)? || content_length(&request.headers, config.max_body)? > 0;
+    if !announced {
+        return Ok(Vec::new());
+    }
+    // These methods have no use for a body, but whatever the client sent
+    // still has to be consumed so the next request on the connection starts
+    // at the right place.
+    let unused = matches!(request.method.as_str(), "GET" | "HEAD" | "DELETE");
+    if unused && config.body_policy == BodyPolicy::Reject {
+        return Err(RequestError::BadRequest("unexpected request body").into());
+    }
+    if request.expects_continue() {
+        conn.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .and_then(|_| conn.flush())
+            .context("Failed to write 100 Continue")?;
+    }
+    let body = read_body(conn, &request.headers, config.max_body)?;
+    Ok(if unused { Vec::new() } else { body })
+}
+
+/// Parses the request line and headers of the next request. Once its first
+/// byte arrives, the client has `config.timeout` to send the rest of them,
+/// however it spaces out the bytes.
+pub(crate) fn parse_request<R: BufRead>(
+    reader: &mut R,
+    config: &Config,
+) -> Result<Option<Request>> {
+    // Waiting for a request to start is covered by the idle timeout alone.
+    match reader.fill_buf() {
+        Ok([]) => return Ok(None),
+        Ok(_) => {}
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context("Failed to read request line"),
+    }
+    let deadline = Instant::now() + config.timeout;
+
+    let mut request_line = String::new();
+    read_line_bounded(
+        reader,
+        &mut request_line,
+        MAX_REQUEST_LINE,
+        RequestError::UriTooLong,
+        Some(deadline),
+    )
+    .context("Failed to read request line")?;
+    reject_bare_cr(&request_line)?;
+
+    // Strictly, the tokens are separated by exactly one space. Parsers that
+    // also split on tabs or runs of spaces can disagree with a proxy in
+    // front about where the target ends.
+    let parts: Vec<&str> = if config.strict_request_line {
+        let line = request_line.trim_end_matches('\n').trim_end_matches('\r');
+        let parts: Vec<&str> = line.split(' ').collect();
+        if parts
+            .iter()
+            .any(|part| part.is_empty() || part.contains(|c: char| c.is_ascii_whitespace()))
+        {
+            return Err(RequestError::BadRequest("malformed request line").into());
+        }
+        parts
+    } else {
+        request_line.split_whitespace().collect()
+    };
+    let [method, target, version] = parts[..] else {
+        return Err(RequestError::BadRequest("malformed request line").into());
+    };
+    if !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+        return Err(RequestError::BadRequest("unsupported HTTP version").into());
+    }
+    let method = method.to_string();
+    // Decoded here so that every handler, and the containment check on
+    // /files paths in particular, sees the real path. The query is split off
+    // first, since a decoded `%3F` is part of the path.
+    if target == "*" && method != "OPTIONS" {
+        return Err(RequestError::BadRequest("asterisk-form is only for OPTIONS").into());
+    }
+    let (path, query) = split_target(target)?;
+    let query = match query {
+        Some(query) => parse_query(query, config.duplicate_query)?,
+        None => Query::default(),
+    };
+    let path = percent_decode(path)?;
+    let version = version.to_string();
+
+    let headers = read_headers(reader, deadline).with_context(|| RequestLine {
+        method: method.clone(),
+        path: path.clone(),
+    })?;
+
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+        peer: None,
+    }))
+}
+
+/// Reads header lines up to the empty line that ends them.
+fn read_headers<R: BufRead>(reader: &mut R, deadline: Instant) -> Result<Headers> {
+    let mut headers = Headers::default();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = read_line_bounded(
+            reader,
+            &mut line,
+            MAX_HEADERS_SIZE - size,
+            RequestError::HeadersTooLarge,
+            Some(deadline),
+        )
+        .context("Failed to read header line")?;
+        size += read;
+        // Otherwise this would pass for the empty line ending the headers.
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                .context("Connection closed during headers");
+        }
+        reject_bare_cr(&line)?;
+        if line.trim().is_empty() {
+            break;
+        }
+        // A line starting with whitespace continues the previous one. That
+        // folding is obsolete and a known source of parser disagreements.
+        if line.starts_with([' ', '\t']) {
+            return Err(RequestError::BadRequest("obsolete header line folding").into());
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(RequestError::BadRequest("malformed header line").into());
+        };
+        // "Transfer-Encoding : chunked" would be read as the header by some
+        // parsers and not others, which is how requests get smuggled.
+        if name.is_empty() || name.contains([' ', '\t']) {
+            return Err(RequestError::BadRequest("malformed header name").into());
+        }
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Like `BufRead::read_line`, but gives up with `too_long` once the line
+/// runs past `limit` bytes, before any more of it is buffered. With a
+/// `deadline`, it also fails with `TimedOut` once that has passed, so a
+/// client can't hold a connection by trickling bytes in just under the read
+/// timeout.
+fn read_line_bounded<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    limit: usize,
+    too_long: RequestError,
+    deadline: Option<Instant>,
+) -> Result<usize> {
+    let mut bytes = Vec::new();
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+        }
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if available.is_empty() {
+            break;
+        }
+        let (taken, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        if bytes.len() + taken > limit {
+            return Err(too_long.into());
+        }
+        bytes.extend_from_slice(&available[..taken]);
+        reader.consume(taken);
+        if done {
+            break;
+        }
+    }
+    let text =
+        String::from_utf8(bytes).map_err(|_| RequestError::BadRequest("request is not UTF-8"))?;
+    line.push_str(&text);
+    Ok(text.len())
+}
+
+/// Splits a request target into its path and query. The absolute form
+/// proxies send, `http://host:port/path?query`, is cut down to those, and
+/// `*` is kept as it is for `OPTIONS *`. Anything else is not a target.
+pub(crate) fn split_target(target: &str) -> Result<(&str, Option<&str>)> {
+    let origin = if target.starts_with('/') || target == "*" {
+        target
+    } else {
+        let scheme = ["http://", "https://"].into_iter().find(|scheme| {
+            target
+                .get(..scheme.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        });
+        let Some(scheme) = scheme else {
+            return Err(RequestError::BadRequest("malformed request target").into());
+        };
+        let rest = &target[scheme.len()..];
+        &rest[rest.find(['/', '?']).unwrap_or(rest.len())..]
+    };
+    let (path, query) = match origin.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (origin, None),
+    };
+    // An absolute target without a path is for the root.
+    Ok((if path.is_empty() { "/" } else { path }, query))
+}
+
+/// A CR is only allowed as part of the line's CRLF ending. Anywhere else it is
+/// a framing error that different parsers disagree on, which is exactly what
+/// header injection and smuggling tricks rely on.
+pub(crate) fn reject_bare_cr(line: &str) -> Result<()> {
+    let content = line.strip_suffix('\n').unwrap_or(line);
+    let content = content.strip_suffix('\r').unwrap_or(content);
+    if content.contains('\r') {
+        return Err(RequestError::BadRequest("bare CR in request").into());
+    }
+    Ok(())
+}
+
+/// Decodes `%XX` escapes. A `+` is left as it is: it only stands for a space
+/// in query strings, not in paths.
+pub(crate) fn percent_decode(input: &str) -> Result<String> {
+    let invalid = || RequestError::BadRequest("invalid percent-encoding");
+    let hex_digit = |byte: Option<&u8>| byte.and_then(|&b| char::from(b).to_digit(16));
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let high = hex_digit(bytes.get(i + 1)).ok_or_else(invalid)?;
+            let low = hex_digit(bytes.get(i + 2)).ok_or_else(invalid)?;
+            decoded.push((high * 16 + low) as u8);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded).map_err(|_| invalid())?)
+}
+
+/// Splits a query string into its decoded `name=value` pairs, keeping the
+/// values of a repeated name that `duplicates` says to. A pair without `=`
+/// has an empty value.
+pub(crate) fn parse_query(query: &str, duplicates: DuplicateQuery) -> Result<Query> {
+    let mut params = Query::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode = |part: &str| percent_decode(&part.replace('+', " "));
+        let (name, value) = (decode(name)?, decode(value)?);
+        let seen = params.pairs.iter().position(|(key, _)| *key == name);
+        match (duplicates, seen) {
+            (DuplicateQuery::First, Some(_)) => {}
+            (DuplicateQuery::Last, Some(i)) => params.pairs[i].1 = value,
+            _ => params.pairs.push((name, value)),
+        }
+    }
+    Ok(params)
+}
+
+/// The request's `Content-Length`, rejected with a 413 when it is over
+/// `max_body` so that nothing that large is ever allocated.
+pub(crate) fn content_length(headers: &Headers, max_body: usize) -> Result<usize> {
+    let content_length: usize = match headers.get("content-length") {
+        Some(value) => value
+            .parse()
+            .map_err(|e: std::num::ParseIntError| match e.kind() {
+                std::num::IntErrorKind::PosOverflow => RequestError::PayloadTooLarge(usize::MAX),
+                _ => RequestError::BadRequest("invalid Content-Length"),
+            })?,
+        None => 0,
+    };
+    if content_length > max_body {
+        return Err(RequestError::PayloadTooLarge(content_length).into());
+    }
+    Ok(content_length)
+}
+
+/// Whether the body is chunked. Chunked has to be the final transfer
+/// coding, and come only once, or there's no telling where the body ends.
+pub(crate) fn is_chunked(headers: &Headers) -> Result<bool> {
+    let Some(value) = headers.get("transfer-encoding") else {
+        return Ok(false);
+    };
+    let codings: Vec<&str> = value.split(',').map(str::trim).collect();
+    match codings
+        .iter()
+        .position(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        Some(i) if i == codings.len() - 1 => Ok(true),
+        _ => Err(RequestError::BadRequest("chunked is not the final transfer coding").into()),
+    }
+}
+
+pub(crate) fn read_body<R: BufRead>(
+    reader: &mut R,
+    headers: &Headers,
+    max_body: usize,
+) -> Result<Vec<u8>> {
+    // Chunked framing wins over any Content-Length that came along with it.
+    if is_chunked(headers)? {
+        return read_chunked_body(reader, max_body);
+    }
+
+    let content_length = content_length(headers, max_body)?;
+    let mut body = Vec::new();
+    read_exactly(reader, content_length, &mut body).context("Failed to read body")?;
+    Ok(body)
+}
+
+/// Appends exactly `len` bytes from `reader` to `body`. The buffer grows as
+/// the bytes arrive rather than up front, so a client can't make us allocate
+/// a body it never sends.
+pub(crate) fn read_exactly<R: Read>(
+    reader: &mut R,
+    len: usize,
+    body: &mut Vec<u8>,
+) -> io::Result<()> {
+    let read = reader.take(len as u64).read_to_end(body)?;
+    if read < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+pub(crate) fn read_chunked_body<R: BufRead>(reader: &mut R, max_body: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        read_line_bounded(
+            reader,
+            &mut line,
+            MAX_CHUNK_LINE,
+            RequestError::BadRequest("chunk size line too long"),
+            None,
+        )
+        .context("Failed to read chunk size")?;
+        // Anything after a ';' is a chunk extension, which we ignore.
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| RequestError::BadRequest("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+
+        let total = body.len().saturating_add(size);
+        if total > max_body {
+            return Err(RequestError::PayloadTooLarge(total).into());
+        }
+        read_exactly(reader, size, &mut body).context("Failed to read chunk")?;
+
+        let mut crlf = [0; 2];
+        reader
+            .read_exact(&mut crlf)
+            .context("Failed to read chunk")?;
+        if &crlf != b"\r\n" {
+            return Err(RequestError::BadRequest("malformed chunk").into());
+        }
+    }
+
+    // Skip any trailer fields up to the terminating empty line. Together
+    // they get the same budget as the headers, so they can't go on forever.
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = read_line_bounded(
+            reader,
+            &mut line,
+            MAX_HEADERS_SIZE - size,
+            RequestError::HeadersTooLarge,
+            None,
+        )
+        .context("Failed to read trailer")?;
+        size += read;
+        if read == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escape::percent_encode;
+    use crate::testing::Duplex;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn read(raw: &str) -> Result<Option<Request>> {
+        read_request(&mut Duplex::new(raw), &Config::default())
+    }
+
+    /// The status a request that fails to parse is answered with.
+    fn rejection(raw: &str) -> StatusCode {
+        read(raw)
+            .unwrap_err()
+            .downcast_ref::<RequestError>()
+            .expect("a rejected request")
+            .status()
+    }
+
+    #[test]
+    fn malformed_requests_are_bad_requests() {
+        assert_eq!(rejection("GET /\r\n\r\n"), StatusCode::BadRequest);
+        assert_eq!(
+            rejection("POST /files/x HTTP/1.1\r\nContent-Length: ten\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(
+            rejection("GET / HTTP/1.1\r\nHost localhost\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn request_lines_need_three_tokens() {
+        assert_eq!(rejection("GET\r\n\r\n"), StatusCode::BadRequest);
+        assert_eq!(rejection("GET /\r\n\r\n"), StatusCode::BadRequest);
+        assert_eq!(
+            rejection("GET / HTTP/1.1 extra\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(rejection("GET / HTTP/2.0\r\n\r\n"), StatusCode::BadRequest);
+        assert_eq!(rejection("\r\n\r\n"), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn an_empty_connection_has_no_request() {
+        assert!(read("").unwrap().is_none());
+    }
+
+    #[test]
+    fn strict_request_lines_take_single_spaces_only() {
+        let lenient = Config {
+            strict_request_line: false,
+            ..Config::default()
+        };
+        let parse = |raw: &str, config: &Config| read_request(&mut Duplex::new(raw), config);
+
+        for raw in [
+            "GET\t/echo/a\tHTTP/1.1\r\n\r\n",
+            "GET  /echo/a HTTP/1.1\r\n\r\n",
+            "GET /echo/a HTTP/1.1 \r\n\r\n",
+        ] {
+            // Strict is the default.
+            assert_eq!(rejection(raw), StatusCode::BadRequest, "{:?}", raw);
+            let request = parse(raw, &lenient).unwrap().unwrap();
+            assert_eq!(request.path, "/echo/a");
+        }
+        assert_eq!(
+            read("GET /echo/a HTTP/1.1\r\n\r\n").unwrap().unwrap().path,
+            "/echo/a"
+        );
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_too_large() {
+        let limit = Config::default().max_body;
+        let raw = |length: usize| {
+            format!(
+                "POST /files/x HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                length
+            )
+        };
+        assert_eq!(rejection(&raw(limit + 1)), StatusCode::PayloadTooLarge);
+        assert_eq!(rejection(&raw(usize::MAX)), StatusCode::PayloadTooLarge);
+        // At the limit it is fine, and only fails for being cut short.
+        let err = read(&raw(limit)).unwrap_err();
+        assert!(err.downcast_ref::<RequestError>().is_none(), "{:#}", err);
+    }
+
+    #[test]
+    fn bare_carriage_returns_are_bad_requests() {
+        for raw in [
+            "GET /echo/a\rb HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Smuggled: a\rTransfer-Encoding: chunked\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: localhost\r\r\n\r\n",
+        ] {
+            assert_eq!(rejection(raw), StatusCode::BadRequest, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn oversized_lines_are_rejected_before_they_are_buffered() {
+        let target = "a".repeat(MAX_REQUEST_LINE);
+        assert_eq!(
+            rejection(&format!("GET /{} HTTP/1.1\r\n\r\n", target)),
+            StatusCode::UriTooLong
+        );
+
+        let value = "a".repeat(MAX_HEADERS_SIZE);
+        assert_eq!(
+            rejection(&format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", value)),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+        // Many small headers add up too.
+        let many = "X-Small: aaaaaaaaaaaaaaaaaaaaaaaa\r\n".repeat(MAX_HEADERS_SIZE / 32 + 1);
+        assert_eq!(
+            rejection(&format!("GET / HTTP/1.1\r\n{}\r\n", many)),
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+
+        let extension = "x".repeat(MAX_CHUNK_LINE);
+        assert_eq!(
+            rejection(&format!(
+                "POST /files/x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\na\r\n0\r\n\r\n",
+                extension
+            )),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn escapes_in_the_path_are_decoded() {
+        let request = read("GET /files/my%20notes.txt HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path, "/files/my notes.txt");
+    }
+
+    #[test]
+    fn percent_decode_round_trips_encoded_paths() {
+        for (encoded, decoded) in [
+            ("/files/caf%C3%A9.txt", "/files/café.txt"),
+            ("/files/%E6%97%A5%E6%9C%AC", "/files/日本"),
+            ("/echo/a%2Fb%3Fc", "/echo/a/b?c"),
+            ("/echo/1+1", "/echo/1+1"),
+            ("/plain", "/plain"),
+        ] {
+            assert_eq!(percent_decode(encoded).unwrap(), decoded);
+            let segments: Vec<String> = decoded.split('/').map(percent_encode).collect();
+            assert_eq!(percent_decode(&segments.join("/")).unwrap(), decoded);
+        }
+    }
+
+    #[test]
+    fn targets_split_into_path_and_query() {
+        for (target, expected) in [
+            (
+                "/echo/abc?repeat=3&x=y",
+                ("/echo/abc", Some("repeat=3&x=y")),
+            ),
+            ("/echo/abc", ("/echo/abc", None)),
+            ("/echo/abc?", ("/echo/abc", Some(""))),
+            (
+                "http://localhost:4221/echo/abc?repeat=2",
+                ("/echo/abc", Some("repeat=2")),
+            ),
+            ("HTTPS://example.com:8443", ("/", None)),
+            ("http://example.com?q=1", ("/", Some("q=1"))),
+            (
+                "/files/what%3F.txt?download=1",
+                ("/files/what%3F.txt", Some("download=1")),
+            ),
+            ("*", ("*", None)),
+        ] {
+            assert_eq!(split_target(target).unwrap(), expected, "{}", target);
+        }
+        assert!(split_target("localhost:4221/echo").is_err());
+    }
+
+    #[test]
+    fn an_encoded_question_mark_stays_in_the_path() {
+        let request = read("GET /echo/what%3F?repeat=2 HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path, "/echo/what?");
+        assert_eq!(request.query.get("repeat"), Some("2"));
+    }
+
+    #[test]
+    fn duplicate_query_names_follow_the_policy() {
+        let values = |policy| {
+            let query = parse_query("a=1&b=x&a=2", policy).unwrap();
+            (
+                query.get("a").map(str::to_string),
+                query.get_all("a").collect::<Vec<_>>().join(","),
+                query.pairs().len(),
+            )
+        };
+
+        assert_eq!(
+            values(DuplicateQuery::KeepAll),
+            (Some("1".into()), "1,2".into(), 3)
+        );
+        assert_eq!(
+            values(DuplicateQuery::First),
+            (Some("1".into()), "1".into(), 2)
+        );
+        assert_eq!(
+            values(DuplicateQuery::Last),
+            (Some("2".into()), "2".into(), 2)
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_are_bad_requests() {
+        for target in ["/files/%zz", "/files/%2", "/files/%", "/files/%FF"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert_eq!(rejection(&raw), StatusCode::BadRequest, "{}", target);
+        }
+    }
+
+    fn dechunk(body: &str) -> Result<Vec<u8>> {
+        let mut reader = Cursor::new(body.as_bytes());
+        let decoded = read_chunked_body(&mut reader, 1024)?;
+        // Nothing past the end of the body is consumed.
+        assert_eq!(reader.position() as usize, body.len());
+        Ok(decoded)
+    }
+
+    #[test]
+    fn chunked_bodies_are_reassembled() {
+        assert_eq!(
+            dechunk("4\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n").unwrap(),
+            b"Wikipedia in\r\n\r\nchunks."
+        );
+        assert_eq!(dechunk("0\r\n\r\n").unwrap(), b"");
+
+        // Chunked framing wins over a Content-Length.
+        let request = read(
+            "POST /files/x HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n0\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn chunk_extensions_and_trailers_are_skipped() {
+        assert_eq!(
+            dechunk("3;name=value\r\nabc\r\n1 ; last\r\nd\r\n0\r\nX-Checksum: 1\r\n\r\n").unwrap(),
+            b"abcd"
+        );
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        for body in ["z\r\nabc\r\n0\r\n\r\n", "3\r\nabcd\r\n0\r\n\r\n"] {
+            let err = dechunk(body).unwrap_err();
+            assert!(err.downcast_ref::<RequestError>().is_some(), "{:?}", body);
+        }
+        let err = read_chunked_body(&mut Cursor::new("801\r\n"), 2048).unwrap_err();
+        assert!(err.downcast_ref::<RequestError>().is_some());
+    }
+
+    #[test]
+    fn chunked_has_to_be_the_final_coding() {
+        for coding in ["chunked, gzip", "chunked, chunked", "gzip", "chunked,"] {
+            let raw = format!(
+                "POST /echo HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n",
+                coding
+            );
+            assert_eq!(rejection(&raw), StatusCode::BadRequest, "{:?}", coding);
+        }
+        let request = read(
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: Chunked\r\n\r\n\
+             3\r\nabc\r\n0\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[test]
+    fn trailers_share_one_size_budget() {
+        let trailer = format!("X-Pad: {}\r\n", "a".repeat(100));
+        let body = format!(
+            "0\r\n{}\r\n",
+            trailer.repeat(MAX_HEADERS_SIZE / trailer.len() + 1)
+        );
+        let err = read_chunked_body(&mut Cursor::new(body), 1024).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RequestError>(),
+            Some(RequestError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn header_lookups_ignore_case_and_padding() {
+        let request = read(
+            "GET / HTTP/1.1\r\ncontent-TYPE:   text/plain  \r\nX-Empty:\r\nAccept: a\r\naccept:  b \r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        let headers = &request.headers;
+        for name in ["Content-Type", "content-type", "CONTENT-TYPE"] {
+            assert_eq!(headers.get(name), Some("text/plain"), "{}", name);
+        }
+        assert_eq!(headers.get("x-empty"), Some(""));
+        // Repeated fields are joined into one list.
+        assert_eq!(headers.get("Accept"), Some("a, b"));
+        assert_eq!(headers.get("Content-Length"), None);
+    }
+
+    #[test]
+    fn debug_output_redacts_credentials() {
+        let request = read(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\
+             Proxy-Authorization: Basic cHJveHk6cGFzcw==\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", request.headers),
+            r#"{"Host": "localhost", "Authorization": "[redacted]", "Proxy-Authorization": "[redacted]"}"#
+        );
+    }
+
+    #[test]
+    fn errors_after_the_request_line_name_the_request() {
+        let err = read("POST /files/x HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap_err();
+        let line = err.downcast_ref::<RequestLine>().expect("request line");
+        assert_eq!(
+            (line.method.as_str(), line.path.as_str()),
+            ("POST", "/files/x")
+        );
+        assert!(err.downcast_ref::<RequestError>().is_some());
+
+        let err = read("GET\r\n\r\n").unwrap_err();
+        assert!(err.downcast_ref::<RequestLine>().is_none());
+    }
+}
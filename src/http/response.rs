@@ -0,0 +1,626 @@
+//! Responses and how they are written to the wire.
+
+use crate::compression::{maybe_compress, preferred_encoding, worth_compressing, Encoding};
+use crate::config::Config;
+use crate::http::request::Request;
+use crate::http::StatusCode;
+use anyhow::Result;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Response headers that must not be sent more than once.
+pub(crate) const SINGLE_VALUED_HEADERS: [&str; 9] = [
+    "Content-Length",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Range",
+    "Transfer-Encoding",
+    "ETag",
+    "Last-Modified",
+    "Location",
+    "Retry-After",
+];
+
+/// Headers `limit_headers` never drops, because without them the client
+/// can't make sense of the body, or of where the response ends, or the
+/// status loses its meaning: a redirect without `Location`, a 405 without
+/// `Allow`, a 401 without `WWW-Authenticate` or a 503 without `Retry-After`.
+pub(crate) const ESSENTIAL_HEADERS: [&str; 10] = [
+    "Content-Length",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Range",
+    "Transfer-Encoding",
+    "Connection",
+    "Location",
+    "Allow",
+    "WWW-Authenticate",
+    "Retry-After",
+];
+
+/// A response body, either held in memory or streamed from a reader.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Copied to the client `CHUNK_SIZE` bytes at a time. Without a known
+    /// length it is sent with chunked transfer encoding.
+    Reader(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl Body {
+    pub(crate) fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader(_, length) => *length,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader(_, length) => f.debug_tuple("Reader").field(length).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// `Link` values to announce in a 103 Early Hints response ahead of
+    /// this one.
+    pub early_hints: Vec<String>,
+}
+
+impl Response {
+    /// An empty response with the given status.
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+            early_hints: Vec::new(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Response::new(StatusCode::Ok)
+    }
+
+    /// Adds a header, keeping any others of the same name.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn content_type(self, content_type: &str) -> Self {
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Compresses an in-memory body with the best coding the request
+    /// allows, unless it is too small or compressed already, and sets
+    /// Content-Encoding and Content-Length to match. This is the one place
+    /// that decision is made for those bodies. A request that rules out
+    /// every coding gets a 406 instead.
+    pub fn with_encoding(mut self, request: &Request, config: &Config) -> Result<Response> {
+        let Some(encoding) = preferred_encoding(request) else {
+            return Ok(not_acceptable());
+        };
+        let Body::Bytes(body) = &self.body else {
+            return Ok(self);
+        };
+        let content_type = self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.as_str());
+        let worth = worth_compressing(content_type, Some(body.len() as u64));
+        let encoding = if worth { encoding } else { Encoding::Identity };
+
+        let (body, content_encoding) = maybe_compress(body, encoding, request, config)?;
+        if worth {
+            self.vary_on_encoding(config);
+        }
+        if let Some(token) = content_encoding {
+            self.set_header("Content-Encoding", token);
+        }
+        self.set_header("Content-Length", &body.len().to_string());
+        self.body = Body::Bytes(body);
+        Ok(self)
+    }
+
+    /// Replaces any existing header called `name`, or adds it.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Adds `name` to the `Vary` header, creating it if need be.
+    pub fn add_vary(&mut self, name: &str) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Vary"))
+        {
+            Some((_, value)) => {
+                if !value
+                    .split(',')
+                    .any(|listed| listed.trim().eq_ignore_ascii_case(name))
+                {
+                    value.push_str(", ");
+                    value.push_str(name);
+                }
+            }
+            None => self.headers.push(("Vary".to_string(), name.to_string())),
+        }
+    }
+
+    /// Marks a response whose body could have been compressed as depending
+    /// on the client's `Accept-Encoding`, so a shared cache doesn't hand a
+    /// compressed copy to a client that can't decode it. Without on-the-fly
+    /// compression there is nothing to negotiate.
+    pub(crate) fn vary_on_encoding(&mut self, config: &Config) {
+        if config.compression_level > 0 {
+            self.add_vary("Accept-Encoding");
+        }
+    }
+
+    /// Drops the body for a HEAD response, keeping the framing headers that
+    /// describe the body a GET would have received.
+    pub fn strip_body(&mut self, chunked: bool) {
+        match self.body.len() {
+            Some(length) if !self.has_header("Content-Length") => {
+                self.set_header("Content-Length", &length.to_string())
+            }
+            None if chunked => self.set_header("Transfer-Encoding", "chunked"),
+            // A GET would get a body delimited by closing, which announces no
+            // length. Still of unknown length, the empty body gets none
+            // either.
+            None => {
+                self.body = Body::Reader(Box::new(io::empty()), None);
+                return;
+            }
+            _ => {}
+        }
+        self.body = Body::Bytes(vec![]);
+    }
+
+    /// Keeps the header section within `budget` bytes, keeping headers in
+    /// order as long as they fit and dropping the rest. The
+    /// `ESSENTIAL_HEADERS` are always kept. Returns how many were dropped.
+    pub fn limit_headers(&mut self, budget: usize) -> usize {
+        let size = |(key, value): &(String, String)| key.len() + value.len() + 4;
+        let essential = |key: &str| {
+            ESSENTIAL_HEADERS
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(key))
+        };
+        let total: usize = self.headers.iter().map(size).sum();
+        if total <= budget {
+            return 0;
+        }
+
+        let mut remaining = budget.saturating_sub(
+            self.headers
+                .iter()
+                .filter(|(key, _)| essential(key))
+                .map(size)
+                .sum(),
+        );
+        let before = self.headers.len();
+        self.headers.retain(|header| {
+            if essential(&header.0) {
+                return true;
+            }
+            let fits = size(header) <= remaining;
+            if fits {
+                remaining -= size(header);
+            }
+            fits
+        });
+        before - self.headers.len()
+    }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status.code()
+    }
+
+    /// Writes the response to `writer`, returning the number of body bytes
+    /// sent. A body of unknown length is framed with chunked encoding if
+    /// `chunked` is set, otherwise it runs until the connection is closed.
+    pub fn write_to<W: Write>(self, writer: &mut W, chunked: bool) -> io::Result<u64> {
+        let mut head = Vec::new();
+        head.extend_from_slice(format!("HTTP/1.1 {}\r\n", self.status).as_bytes());
+        for (i, (key, value)) in self.headers.iter().enumerate() {
+            // Of a header that may only appear once, the last value wins.
+            let superseded = SINGLE_VALUED_HEADERS
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(key))
+                && self.headers[i + 1..]
+                    .iter()
+                    .any(|(later, _)| later.eq_ignore_ascii_case(key));
+            if !superseded {
+                head.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+            }
+        }
+        // Clients on a persistent connection rely on the length to find
+        // where this response ends.
+        // 204 and 304 responses never have a body, so they must not
+        // announce one either.
+        let length = self.body.len();
+        let has_body = !matches!(self.status_code(), 204 | 304);
+        if has_body && !self.has_header("Content-Length") && !self.has_header("Transfer-Encoding") {
+            match length {
+                Some(length) => {
+                    head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes())
+                }
+                None if chunked => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+        head.extend_from_slice(b"\r\n");
+
+        match self.body {
+            Body::Bytes(bytes) => {
+                head.extend_from_slice(&bytes);
+                writer.write_all(&head)?;
+                Ok(bytes.len() as u64)
+            }
+            Body::Reader(mut reader, length) => {
+                writer.write_all(&head)?;
+                copy_body(&mut reader, writer, chunked && length.is_none())
+            }
+        }
+    }
+}
+
+/// The 406 for a request whose `Accept-Encoding` rules out every coding.
+pub(crate) fn not_acceptable() -> Response {
+    let mut response = error_response(StatusCode::NotAcceptable);
+    response.add_vary("Accept-Encoding");
+    response
+}
+
+/// Copies `reader` to `writer` a chunk at a time, optionally framing each
+/// piece for chunked transfer encoding. Returns the number of bytes copied.
+pub(crate) fn copy_body<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    chunked: bool,
+) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if chunked {
+            write!(writer, "{:x}\r\n", n)?;
+            writer.write_all(&buf[..n])?;
+            writer.write_all(b"\r\n")?;
+        } else {
+            writer.write_all(&buf[..n])?;
+        }
+        copied += n as u64;
+    }
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    Ok(copied)
+}
+
+/// A `text/plain` response whose body is just the status, e.g. `404 Not Found`.
+pub fn error_response(status: StatusCode) -> Response {
+    Response::new(status)
+        .content_type("text/plain")
+        .body(status.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{request, respond, Wire};
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use pretty_assertions::assert_eq;
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes)
+            .read_to_end(&mut decoded)
+            .expect("valid gzip");
+        decoded
+    }
+
+    fn bytes(response: &Response) -> &[u8] {
+        match &response.body {
+            Body::Bytes(bytes) => bytes,
+            Body::Reader(..) => panic!("expected an in-memory body"),
+        }
+    }
+
+    #[test]
+    fn with_encoding_gzips_for_a_client_that_accepts_it() {
+        let text = "hello ".repeat(100);
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let response = Response::ok()
+            .content_type("text/plain")
+            .body(text.as_bytes())
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        let body = bytes(&response);
+        assert!(response
+            .headers
+            .contains(&("Content-Encoding".into(), "gzip".into())));
+        assert!(response
+            .headers
+            .contains(&("Content-Length".into(), body.len().to_string())));
+        assert_eq!(gunzip(body), text.as_bytes());
+    }
+
+    #[test]
+    fn with_encoding_deflates_as_zlib() {
+        let text = "hello ".repeat(100);
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: deflate\r\n\r\n");
+        let response = Response::ok()
+            .content_type("text/plain")
+            .body(text.as_bytes())
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        assert!(response
+            .headers
+            .contains(&("Content-Encoding".into(), "deflate".into())));
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(bytes(&response))
+            .read_to_end(&mut decoded)
+            .expect("valid zlib");
+        assert_eq!(decoded, text.as_bytes());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn with_encoding_uses_zstd_for_a_client_that_accepts_it() {
+        let text = "hello ".repeat(100);
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: gzip;q=0.8, zstd\r\n\r\n");
+        let response = Response::ok()
+            .content_type("text/plain")
+            .body(text.as_bytes())
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        assert!(response
+            .headers
+            .contains(&("Content-Encoding".into(), "zstd".into())));
+        let decoded = zstd::decode_all(bytes(&response)).expect("valid zstd");
+        assert_eq!(decoded, text.as_bytes());
+    }
+
+    #[test]
+    fn with_encoding_sends_identity_without_accept_encoding() {
+        let text = "hello ".repeat(100);
+        let request = request("GET / HTTP/1.1\r\n\r\n");
+        let response = Response::ok()
+            .content_type("text/plain")
+            .body(text.as_bytes())
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        assert!(!response.has_header("Content-Encoding"));
+        assert!(response
+            .headers
+            .contains(&("Content-Length".into(), text.len().to_string())));
+        assert_eq!(bytes(&response), text.as_bytes());
+    }
+
+    #[test]
+    fn with_encoding_marks_negotiated_bodies_as_varying() {
+        let vary = |accept: &str, body: &str| {
+            let request = request(&format!(
+                "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                accept
+            ));
+            let response = Response::ok()
+                .content_type("text/plain")
+                .body(body)
+                .with_encoding(&request, &Config::default())
+                .unwrap();
+            response
+                .headers
+                .iter()
+                .find(|(name, _)| name == "Vary")
+                .map(|(_, value)| value.clone())
+        };
+        let text = "hello ".repeat(100);
+        // Whatever was picked this time, another client could get another
+        // coding.
+        assert_eq!(vary("gzip", &text).as_deref(), Some("Accept-Encoding"));
+        assert_eq!(vary("identity", &text).as_deref(), Some("Accept-Encoding"));
+        assert_eq!(
+            vary("identity;q=0", &text).as_deref(),
+            Some("Accept-Encoding")
+        );
+        // Too short to ever be compressed.
+        assert_eq!(vary("gzip", "hi"), None);
+    }
+
+    #[test]
+    fn add_vary_extends_an_existing_header() {
+        let mut response = Response::ok().header("Vary", "Accept");
+        response.add_vary("Accept-Encoding");
+        response.add_vary("accept-encoding");
+        assert_eq!(
+            response.headers,
+            vec![("Vary".to_string(), "Accept, Accept-Encoding".to_string())]
+        );
+    }
+
+    #[test]
+    fn limit_headers_keeps_what_the_status_needs() {
+        let padding = "x".repeat(100);
+        for (status, name) in [
+            (StatusCode::MovedPermanently, "Location"),
+            (StatusCode::MethodNotAllowed, "Allow"),
+            (StatusCode::Unauthorized, "WWW-Authenticate"),
+            (StatusCode::ServiceUnavailable, "Retry-After"),
+        ] {
+            let mut response = Response::new(status)
+                .header("X-Padding", &padding)
+                .header(name, "value");
+            assert_eq!(response.limit_headers(64), 1);
+            assert_eq!(
+                response.headers,
+                vec![(name.to_string(), "value".to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn with_encoding_leaves_an_empty_body_alone() {
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let response = Response::ok()
+            .content_type("text/plain")
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        assert!(!response.has_header("Content-Encoding"));
+        assert!(response
+            .headers
+            .contains(&("Content-Length".into(), "0".into())));
+        assert_eq!(bytes(&response), b"");
+    }
+
+    #[test]
+    fn with_encoding_round_trips_a_binary_body() {
+        let binary: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let request = request("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let response = Response::ok()
+            .content_type("application/octet-stream")
+            .body(binary.clone())
+            .with_encoding(&request, &Config::default())
+            .unwrap();
+
+        assert_eq!(gunzip(bytes(&response)), binary);
+    }
+
+    #[test]
+    fn write_to_frames_an_in_memory_body_with_its_length() {
+        let mut wire = Vec::new();
+        let sent = Response::ok()
+            .content_type("text/plain")
+            .body("abc")
+            .write_to(&mut wire, true)
+            .unwrap();
+
+        assert_eq!(sent, 3);
+        let wire = Wire::parse(&wire);
+        assert_eq!(wire.status, 200);
+        assert_eq!(wire.header("Content-Length"), Some("3"));
+        assert_eq!(wire.body, b"abc");
+    }
+
+    #[test]
+    fn the_status_line_carries_the_chosen_status() {
+        for status in [
+            StatusCode::Ok,
+            StatusCode::Created,
+            StatusCode::NotFound,
+            StatusCode::MethodNotAllowed,
+            StatusCode::InternalServerError,
+        ] {
+            let response = error_response(status);
+            assert_eq!(response.status_code(), status.code());
+            let mut wire = Vec::new();
+            response.write_to(&mut wire, true).unwrap();
+
+            assert_eq!(Wire::parse(&wire).status, status.code());
+            let status_line = format!("HTTP/1.1 {} {}\r\n", status.code(), status.reason());
+            assert!(wire.starts_with(status_line.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn single_valued_headers_are_sent_once() {
+        let mut wire = Vec::new();
+        Response::ok()
+            .content_type("application/octet-stream")
+            .header("Content-Length", "99")
+            .header("content-length", "3")
+            .content_type("text/plain")
+            .header("X-Note", "a")
+            .header("X-Note", "b")
+            .body("abc")
+            .write_to(&mut wire, true)
+            .unwrap();
+
+        let wire = Wire::parse(&wire);
+        assert_eq!(wire.count("Content-Length"), 1);
+        assert_eq!(wire.header("Content-Length"), Some("3"));
+        assert_eq!(wire.count("Content-Type"), 1);
+        assert_eq!(wire.header("Content-Type"), Some("text/plain"));
+        // Other headers may repeat.
+        assert_eq!(wire.count("X-Note"), 2);
+    }
+
+    #[test]
+    fn handler_responses_have_one_content_length() {
+        for raw in [
+            "GET /echo/abc HTTP/1.1\r\n\r\n",
+            "GET /echo/abc?repeat=100 HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+            "GET /user-agent HTTP/1.1\r\nUser-Agent: test\r\n\r\n",
+            "GET /nowhere HTTP/1.1\r\n\r\n",
+        ] {
+            let wire = respond(raw, Config::default());
+            assert_eq!(wire.count("Content-Length"), 1, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn write_to_is_byte_exact() {
+        let written = |response: Response| {
+            let mut wire = Vec::new();
+            response.write_to(&mut wire, true).unwrap();
+            String::from_utf8(wire).unwrap()
+        };
+
+        assert_eq!(
+            written(Response::new(StatusCode::Ok)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            written(Response::new(StatusCode::NoContent).header("Allow", "GET")),
+            "HTTP/1.1 204 No Content\r\nAllow: GET\r\n\r\n"
+        );
+        assert_eq!(
+            written(Response::ok().content_type("text/plain").body("abc")),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nabc"
+        );
+        let mut streamed = Response::ok();
+        streamed.body = Body::Reader(Box::new(&b"abc"[..]), None);
+        assert_eq!(
+            written(streamed),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! A small HTTP/1.1 file server.
+
+pub mod auth;
+mod capture;
+pub mod compression;
+pub mod config;
+pub mod connection;
+mod escape;
+pub mod handlers;
+pub mod http;
+pub mod router;
+pub mod server;
+pub mod stats;
+pub mod tls;
+
+#[cfg(test)]
+mod testing;
+
+pub use handlers::{build_manifest, check_listing_template};
+pub use router::Router;
+pub use server::Server;
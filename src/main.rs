@@ -1,332 +1,491 @@
-use anyhow::{Context, Result};
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use anyhow::{anyhow, bail, Context, Result};
+use http_server_starter_rust::{
+    auth::Credentials,
+    build_manifest, check_listing_template,
+    config::{BodyPolicy, Config, DuplicateQuery, EtagMode, ForceConnection, LogLevel},
+    tls::load_server_config,
+    Server,
+};
 use std::{
-    env,
-    fs::File,
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
+    env, fs,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
+    time::Duration,
 };
 
-const DEFAULT_DIRECTORY: &str = ".";
-const ADDRESS: &str = "127.0.0.1:4221";
-const OK_HEADER: &str = "HTTP/1.1 200 OK\r\n\r\n";
-const CREATED_HEADER: &str = "HTTP/1.1 201 Created\r\n\r\n";
-const NOT_FOUND_HEADER: &str = "HTTP/1.1 404 Not Found\r\n\r\n";
-const METHOD_NOT_ALLOWED_HEADER: &str = "HTTP/1.1 405 Method Not Allowed\r\n";
-
-#[derive(Debug)]
-struct Response {
-    status_line: &'static str,
-    headers: Vec<(String, String)>,
-    body: Vec<u8>,
-}
-
-impl Response {
-    fn build(&self) -> Vec<u8> {
-        let mut response = Vec::new();
-        response.extend_from_slice(self.status_line.as_bytes());
-        for (key, value) in &self.headers {
-            response.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+fn main() -> Result<()> {
+    let Args {
+        config,
+        check,
+        mut problems,
+    } = handle_args(env::args().skip(1));
+    if check {
+        problems.extend(check_config(&config));
+        if problems.is_empty() {
+            println!("Configuration OK");
+            return Ok(());
         }
-        response.extend_from_slice(b"\r\n");
-        response.extend_from_slice(&self.body);
-        response
     }
-}
-
-fn main() -> Result<()> {
-    let directory = match handle_args() {
-        Ok(dir) => dir,
-        Err(err) => {
-            eprintln!(
-                "Error: {}. Using default directory: {}",
-                err, DEFAULT_DIRECTORY
-            );
-            DEFAULT_DIRECTORY.to_string()
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("Error: {:#}", problem);
         }
-    };
+        std::process::exit(if check { 1 } else { 2 });
+    }
+    if let Some(credentials) = &config.credentials {
+        let count = credentials.reload()?;
+        println!(
+            "Loaded {} user(s) from {}",
+            count,
+            credentials.path.display()
+        );
+        #[cfg(unix)]
+        reload_credentials_on_hangup(Arc::clone(credentials))?;
+    }
 
-    let listener = TcpListener::bind(ADDRESS)?;
+    if !config.one_shot {
+        if config.manifest {
+            // Hash everything up front so the first request doesn't pay for it.
+            let files = build_manifest(&config.directory)?;
+            println!("Indexed {} file(s) for /manifest.json", files);
+        }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let directory = directory.clone();
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &directory) {
-                        eprintln!("Error handling client: {}", e);
-                    }
-                });
-            }
-            Err(e) => eprintln!("Connection failed: {}", e),
+        if let Some(idle) = config.tcp_keepalive {
+            println!("TCP keepalive probes after {}s idle", idle.as_secs());
         }
+
+        install_shutdown_handler(Arc::clone(&config.shutdown))?;
     }
 
-    Ok(())
+    Server::new(config).run()
 }
 
-fn handle_args() -> Result<String> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 3 && args[1] == "--directory" {
-        Ok(args[2].clone())
-    } else if args.len() > 1 {
-        Err(anyhow::anyhow!("Usage: program --directory <path>"))
-    } else {
-        Ok(DEFAULT_DIRECTORY.to_string())
+/// The startup work that can fail, done up front for `--check` without
+/// starting the server.
+fn check_config(config: &Config) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    if let Err(e) = fs::read_dir(&config.directory)
+        .with_context(|| format!("Failed to read directory {}", config.directory))
+    {
+        problems.push(e);
+    }
+    if let Some(credentials) = &config.credentials {
+        if let Err(e) = credentials.reload() {
+            problems.push(e);
+        }
     }
+    if config.manifest {
+        if let Err(e) = build_manifest(&config.directory) {
+            problems.push(e);
+        }
+    }
+    problems
 }
 
-fn handle_client(mut stream: TcpStream, directory: &str) -> Result<()> {
-    let mut buf_reader = BufReader::new(&mut stream);
-
-    let (method, path, headers) = parse_request(&mut buf_reader)?;
-    let body = read_body(&mut buf_reader, &headers)?;
-
-    let response = match method.as_str() {
-        "POST" => handle_post(&path, &body, directory),
-        "GET" => handle_get(&path, &headers, directory),
-        _ => Ok(Response {
-            status_line: METHOD_NOT_ALLOWED_HEADER,
-            headers: vec![],
-            body: vec![],
-        }),
-    }?;
-
-    stream.write_all(&response.build())?;
-    stream.flush()?;
+/// Flips `shutdown` when the process receives Ctrl-C, or SIGTERM on Unix.
+fn install_shutdown_handler(shutdown: Arc<AtomicBool>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build signal runtime")?;
+    thread::spawn(move || {
+        runtime.block_on(async {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                match signal(SignalKind::terminate()) {
+                    Ok(mut terminate) => {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => {}
+                            _ = terminate.recv() => {}
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to listen for SIGTERM: {}", e);
+                        let _ = tokio::signal::ctrl_c().await;
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
 
-    println!("Response sent successfully");
+            shutdown.store(true, Ordering::SeqCst);
+        })
+    });
     Ok(())
 }
 
-fn parse_request<R: BufRead>(reader: &mut R) -> Result<(String, String, String)> {
-    let mut request_line = String::new();
-    reader
-        .read_line(&mut request_line)
-        .context("Failed to read request line")?;
-    let request_line = request_line.trim();
+/// Reloads the `--auth-file` whenever the process receives SIGHUP.
+#[cfg(unix)]
+fn reload_credentials_on_hangup(credentials: Arc<Credentials>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build signal runtime")?;
+    thread::spawn(move || {
+        runtime.block_on(async {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    eprintln!("Failed to listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                match credentials.reload() {
+                    Ok(count) => println!("Reloaded {} user(s)", count),
+                    Err(e) => eprintln!("Keeping previous users, reload failed: {}", e),
+                }
+            }
+        })
+    });
+    Ok(())
+}
 
-    let mut parts = request_line.split_whitespace();
-    let method = parts.next().unwrap_or("").to_string();
-    let path = parts.next().unwrap_or("").to_string();
+/// The parsed command line. Invalid flags, and files named on it that can't
+/// be used, end up in `problems` rather than failing on the first, so they
+/// can all be reported at once.
+struct Args {
+    config: Config,
+    check: bool,
+    problems: Vec<anyhow::Error>,
+}
 
-    let mut headers = String::new();
-    loop {
-        let mut line = String::new();
-        reader
-            .read_line(&mut line)
-            .context("Failed to read header line")?;
-        if line.trim().is_empty() {
-            break;
+fn handle_args(mut args: impl Iterator<Item = String>) -> Args {
+    let mut config = Config::default();
+    let mut check = false;
+    let mut problems = Vec::new();
+    let mut bad_args = Vec::new();
+    let mut tls_cert: Option<PathBuf> = None;
+    let mut tls_key: Option<PathBuf> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" => match args.next().and_then(|ip| ip.parse().ok()) {
+                Some(address) => config.address = address,
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--port" => match args.next().and_then(|port| port.parse().ok()) {
+                Some(port) => config.port = port,
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--directory" => match args.next() {
+                Some(dir) => config.directory = dir,
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--one-shot" => config.one_shot = true,
+            "--check" => check = true,
+            "--max-body" | "--max-body-size" => {
+                match args.next().and_then(|bytes| bytes.parse().ok()) {
+                    Some(bytes) => config.max_body = bytes,
+                    None => bad_args.push(invalid_value(&arg)),
+                }
+            }
+            "--unexpected-body" => match args.next().as_deref() {
+                Some("drain") => config.body_policy = BodyPolicy::Drain,
+                Some("reject") => config.body_policy = BodyPolicy::Reject,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--duplicate-query" => match args.next().as_deref() {
+                Some("keep-all") => config.duplicate_query = DuplicateQuery::KeepAll,
+                Some("first") => config.duplicate_query = DuplicateQuery::First,
+                Some("last") => config.duplicate_query = DuplicateQuery::Last,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--secure-headers" => config.secure_headers = true,
+            "--tls-cert" => match args.next() {
+                Some(path) => tls_cert = Some(path.into()),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--tls-key" => match args.next() {
+                Some(path) => tls_key = Some(path.into()),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--max-response-headers" => match args.next().and_then(|bytes| bytes.parse().ok()) {
+                Some(bytes) => config.max_response_headers = bytes,
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--threads" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(threads) if threads > 0 => config.threads = threads,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--fadvise" => config.fadvise = true,
+            "--download-rate" => match args.next().and_then(|rate| rate.parse().ok()) {
+                Some(rate) if rate > 0 => config.download_rate = Some(rate),
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--etag-mode" => match args.next().as_deref() {
+                Some("content") => config.etag_mode = EtagMode::Content,
+                Some("mtime") => config.etag_mode = EtagMode::Mtime,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--log-level" => match args.next().as_deref() {
+                Some("error") => config.log_level = LogLevel::Error,
+                Some("info") => config.log_level = LogLevel::Info,
+                Some("debug") => config.log_level = LogLevel::Debug,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--timeout" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) if secs > 0 => config.timeout = Duration::from_secs(secs),
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--write-timeout" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) if secs > 0 => config.write_timeout = Some(Duration::from_secs(secs)),
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--tcp-keepalive" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) if secs > 0 => config.tcp_keepalive = Some(Duration::from_secs(secs)),
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--auth-file" => match args.next() {
+                Some(path) => config.credentials = Some(Arc::new(Credentials::new(path.into()))),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--early-hints" => config.early_hints = true,
+            "--compression-level" => match args.next().and_then(|level| level.parse().ok()) {
+                Some(level) if level <= 9 => config.compression_level = level,
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--compress-http11-only" => config.compress_http11_only = true,
+            "--precompressed" => config.precompressed = true,
+            "--lenient-request-line" => config.strict_request_line = false,
+            "--manifest" => config.manifest = true,
+            "--enable-listing" => config.enable_listing = true,
+            "--index" => match args.next() {
+                Some(names) if names.split(',').all(|name| !name.trim().is_empty()) => {
+                    config.index_files = names.split(',').map(|n| n.trim().to_string()).collect()
+                }
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--static-site" => config.static_site = true,
+            "--maintenance" => config.maintenance = true,
+            "--cors" => match args.next() {
+                Some(origin) => config.cors_origin = Some(origin),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--retry-after" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => config.retry_after = secs,
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--maintenance-page" => match args.next() {
+                Some(path) => match fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read maintenance page {}", path))
+                {
+                    Ok(page) => config.maintenance_page = Some(page),
+                    Err(e) => problems.push(e),
+                },
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--listing-template" => match args.next() {
+                Some(path) => match fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read listing template {}", path))
+                    .and_then(|template| {
+                        check_listing_template(&template)
+                            .with_context(|| format!("Invalid listing template {}", path))?;
+                        Ok(template)
+                    }) {
+                    Ok(template) => config.listing_template = Some(template),
+                    Err(e) => problems.push(e),
+                },
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            #[cfg(feature = "zstd")]
+            "--zstd-level" => match args.next().and_then(|level| level.parse().ok()) {
+                Some(level) if zstd::compression_level_range().contains(&level) => {
+                    config.zstd_level = level
+                }
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--force-connection" => match args.next().as_deref() {
+                Some("close") => config.force_connection = Some(ForceConnection::Close),
+                Some("keep-alive") => config.force_connection = Some(ForceConnection::KeepAlive),
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--capture-dir" => match args.next() {
+                Some(dir) => config.capture_dir = Some(dir.into()),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--capture-responses" => config.capture_responses = true,
+            "--grace-period" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => config.grace_period = Duration::from_secs(secs),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            "--route-timeout" => match args.next().as_deref().and_then(|r| r.split_once('=')) {
+                Some((prefix, secs)) if prefix.starts_with('/') => match secs.parse() {
+                    Ok(secs) if secs > 0 => config
+                        .route_timeouts
+                        .push((prefix.to_string(), Duration::from_secs(secs))),
+                    _ => bad_args.push(invalid_value(&arg)),
+                },
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--cache-control" => match args.next().as_deref().and_then(|r| r.split_once('=')) {
+                Some((pattern, value)) if pattern.contains('/') && !value.trim().is_empty() => {
+                    config
+                        .cache_control
+                        .push((pattern.trim().to_string(), value.trim().to_string()))
+                }
+                _ => bad_args.push(invalid_value(&arg)),
+            },
+            "--header" => match args.next().as_deref().and_then(|h| h.split_once(':')) {
+                Some((name, value)) => config
+                    .extra_headers
+                    .push((name.trim().to_string(), value.trim().to_string())),
+                None => bad_args.push(invalid_value(&arg)),
+            },
+            _ => bad_args.push(anyhow!("Unknown argument {}", arg)),
         }
-        headers.push_str(&line);
     }
-
-    Ok((method, path, headers))
-}
-
-fn read_body<R: BufRead>(reader: &mut R, headers: &str) -> Result<Vec<u8>> {
-    let content_length: usize = headers
-        .lines()
-        .find(|line| line.to_lowercase().starts_with("content-length:"))
-        .and_then(|line| line.split_whitespace().nth(1)?.parse().ok())
-        .unwrap_or(0);
-
-    let mut body = vec![0; content_length];
-    if content_length > 0 {
-        reader
-            .read_exact(&mut body)
-            .context("Failed to read body")?;
+    if !bad_args.is_empty() {
+        bad_args.push(usage());
     }
-
-    Ok(body)
-}
-
-fn handle_post(path: &str, body: &[u8], directory: &str) -> Result<Response> {
-    if path.starts_with("/files/") {
-        let filename = &path[7..];
-        let filepath = Path::new(directory).join(filename);
-
-        File::create(filepath)?
-            .write_all(body)
-            .context("Failed to write file")?;
-        Ok(Response {
-            status_line: CREATED_HEADER,
-            headers: vec![],
-            body: vec![],
-        })
-    } else {
-        Ok(Response {
-            status_line: METHOD_NOT_ALLOWED_HEADER,
-            headers: vec![],
-            body: vec![],
-        })
+    problems.extend(bad_args);
+    match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => match load_server_config(&cert, &key) {
+            Ok(tls) => config.tls = Some(tls),
+            Err(e) => problems.push(e),
+        },
+        (None, None) => {}
+        _ => problems.push(anyhow!(
+            "--tls-cert and --tls-key have to be given together"
+        )),
     }
-}
-
-fn handle_get(path: &str, headers: &str, directory: &str) -> Result<Response> {
-    if path.starts_with("/files/") {
-        let filename = &path[7..];
-        let filepath = Path::new(directory).join(filename);
-        if filepath.exists() {
-            serve_file(filepath, headers)
+    // --check may come later on the command line, and it mustn't create
+    // anything.
+    if let Some(dir) = &config.capture_dir {
+        let result = if check {
+            check_creatable(dir)
         } else {
-            Ok(Response {
-                status_line: NOT_FOUND_HEADER,
-                headers: vec![],
-                body: vec![],
-            })
+            fs::create_dir_all(dir).map_err(Into::into)
+        };
+        if let Err(e) =
+            result.with_context(|| format!("Failed to create capture directory {}", dir.display()))
+        {
+            problems.push(e);
         }
-    } else if path == "/user-agent" {
-        let user_agent = extract_user_agent(headers)?;
-        serve_user_agent(&user_agent, headers)
-    } else if path.starts_with("/echo/") {
-        serve_echo(path, headers)
-    } else if path == "/" {
-        Ok(Response {
-            status_line: OK_HEADER,
-            headers: vec![],
-            body: vec![],
-        })
-    } else {
-        Ok(Response {
-            status_line: NOT_FOUND_HEADER,
-            headers: vec![],
-            body: vec![],
-        })
     }
-}
-
-fn extract_user_agent(headers: &str) -> Result<String> {
-    for line in headers.lines() {
-        if line.to_lowercase().starts_with("user-agent:") {
-            return Ok(line["User-Agent:".len()..].trim().to_string());
-        }
+    Args {
+        config,
+        check,
+        problems,
     }
-    Ok(String::new())
 }
 
-fn serve_file(filepath: PathBuf, headers: &str) -> Result<Response> {
-    let mut file = File::open(filepath)?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents)
-        .context("Failed to read file")?;
-
-    let content_length = contents.len();
-    let supports_gzip = supports_gzip(headers);
-
-    let mut response = Response {
-        status_line: "HTTP/1.1 200 OK\r\n",
-        headers: vec![(
-            "Content-Type".to_string(),
-            "application/octet-stream".to_string(),
-        )],
-        body: vec![],
-    };
-
-    if supports_gzip {
-        let compressed_contents = compress_gzip(&contents)?;
-        response
-            .headers
-            .push(("Content-Encoding".to_string(), "gzip".to_string()));
-        response.body.extend_from_slice(&compressed_contents);
-        response.headers.push((
-            "Content-Length".to_string(),
-            compressed_contents.len().to_string(),
-        ));
-    } else {
-        response.body.extend_from_slice(&contents);
-        response
-            .headers
-            .push(("Content-Length".to_string(), content_length.to_string()));
-    }
-
-    Ok(response)
+fn invalid_value(flag: &str) -> anyhow::Error {
+    anyhow!("{} needs a valid value", flag)
 }
 
-fn serve_user_agent(user_agent: &str, headers: &str) -> Result<Response> {
-    let supports_gzip = supports_gzip(headers);
-
-    let response_body = user_agent.as_bytes();
-    let content_length = response_body.len();
-
-    let mut response = Response {
-        status_line: "HTTP/1.1 200 OK\r\n",
-        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-        body: vec![],
-    };
-
-    if supports_gzip {
-        let compressed_contents = compress_gzip(response_body)?;
-        response
-            .headers
-            .push(("Content-Encoding".to_string(), "gzip".to_string()));
-        response.body.extend_from_slice(&compressed_contents);
-        response.headers.push((
-            "Content-Length".to_string(),
-            compressed_contents.len().to_string(),
-        ));
-    } else {
-        response.body.extend_from_slice(response_body);
-        response
-            .headers
-            .push(("Content-Length".to_string(), content_length.to_string()));
+/// Fails unless `dir` is a directory, or could be created as one.
+fn check_creatable(dir: &Path) -> Result<()> {
+    match dir.ancestors().find(|ancestor| ancestor.exists()) {
+        Some(existing) if !existing.is_dir() => bail!("{} is not a directory", existing.display()),
+        _ => Ok(()),
     }
-
-    Ok(response)
 }
 
-fn serve_echo(path: &str, headers: &str) -> Result<Response> {
-    let echo_str = &path[6..];
-    let supports_gzip = supports_gzip(headers);
+fn usage() -> anyhow::Error {
+    anyhow!(
+        "Usage: program [--address <ip>] [--port <port>] [--directory <path>] [--one-shot] [--check] \
+         [--max-body-size <bytes>] [--unexpected-body drain|reject] \
+         [--duplicate-query keep-all|first|last] \
+         [--secure-headers] [--tls-cert <pem> --tls-key <pem>] \
+         [--header 'Name: value']... [--max-response-headers <bytes>] [--threads <n>] \
+         [--fadvise] [--download-rate <bytes/sec>] [--etag-mode content|mtime] \
+         [--timeout <secs>] [--write-timeout <secs>] [--tcp-keepalive <secs>] \
+         [--route-timeout <prefix>=<secs>]... [--cache-control <type>=<value>]... \
+         [--auth-file <path>] [--early-hints] \
+         [--grace-period <secs>] [--zstd-level <n>] [--compression-level <0-9>] [--compress-http11-only] [--precompressed] [--log-level error|info|debug] \
+         [--lenient-request-line] [--manifest] [--enable-listing] [--index <name,...>] [--static-site] \
+         [--listing-template <file>] [--maintenance] [--retry-after <secs>] \
+         [--maintenance-page <file>] [--cors <origin>] [--capture-dir <path>] \
+         [--capture-responses] [--force-connection close|keep-alive]"
+    )
+}
 
-    let response_body = echo_str.as_bytes();
-    let content_length = response_body.len();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_server_starter_rust::config::{DEFAULT_ADDRESS, DEFAULT_DIRECTORY, DEFAULT_PORT};
+    use std::net::{IpAddr, Ipv4Addr};
 
-    let mut response = Response {
-        status_line: "HTTP/1.1 200 OK\r\n",
-        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-        body: vec![],
-    };
+    fn parse(args: &[&str]) -> Args {
+        handle_args(args.iter().map(|arg| arg.to_string()))
+    }
 
-    if supports_gzip {
-        let compressed_contents = compress_gzip(response_body)?;
-        response
-            .headers
-            .push(("Content-Encoding".to_string(), "gzip".to_string()));
-        response.body.extend_from_slice(&compressed_contents);
-        response.headers.push((
-            "Content-Length".to_string(),
-            compressed_contents.len().to_string(),
-        ));
-    } else {
-        response.body.extend_from_slice(response_body);
-        response
-            .headers
-            .push(("Content-Length".to_string(), content_length.to_string()));
+    #[test]
+    fn flags_combine_in_any_order() {
+        let args = parse(&[
+            "--port",
+            "8080",
+            "--directory",
+            "/srv",
+            "--address",
+            "0.0.0.0",
+        ]);
+        assert!(args.problems.is_empty());
+        assert_eq!(args.config.address, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(args.config.port, 8080);
+        assert_eq!(args.config.directory, "/srv");
     }
 
-    Ok(response)
-}
+    #[test]
+    fn missing_flags_keep_their_defaults() {
+        let args = parse(&["--port", "9000"]);
+        assert!(args.problems.is_empty());
+        assert_eq!(args.config.address, DEFAULT_ADDRESS);
+        assert_eq!(args.config.port, 9000);
+        assert_eq!(args.config.directory, DEFAULT_DIRECTORY);
+
+        let args = parse(&[]);
+        assert!(args.problems.is_empty());
+        assert_eq!(args.config.port, DEFAULT_PORT);
+    }
 
-fn supports_gzip(headers: &str) -> bool {
-    headers
-        .lines()
-        .find(|line| line.to_lowercase().starts_with("accept-encoding:"))
-        .map(|line| {
-            line["accept-encoding:".len()..]
-                .split(',')
-                .map(str::trim)
-                .any(|encoding| encoding == "gzip")
-        })
-        .unwrap_or(false)
-}
+    #[test]
+    fn every_invalid_flag_is_reported() {
+        let args = parse(&[
+            "--port",
+            "abc",
+            "--address",
+            "nowhere",
+            "--bogus",
+            "--directory",
+        ]);
+        let problems: Vec<String> = args.problems.iter().map(ToString::to_string).collect();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert_eq!(problems[0], "--port needs a valid value");
+        assert_eq!(problems[1], "--address needs a valid value");
+        assert_eq!(problems[2], "Unknown argument --bogus");
+        assert_eq!(problems[3], "--directory needs a valid value");
+        assert!(problems[4].starts_with("Usage:"));
+    }
 
-fn compress_gzip(data: &[u8]) -> Result<Vec<u8>> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(data)?;
-    encoder.finish().map_err(Into::into)
+    #[test]
+    fn tls_needs_both_the_certificate_and_the_key() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        let cert = format!("{}/localhost.pem", fixtures);
+        let key = format!("{}/localhost.key", fixtures);
+
+        let args = parse(&["--tls-cert", &cert, "--tls-key", &key]);
+        assert!(args.problems.is_empty(), "{:?}", args.problems);
+        assert!(args.config.tls.is_some());
+
+        for half in [["--tls-cert", &cert], ["--tls-key", &key]] {
+            let problems: Vec<String> = parse(&half)
+                .problems
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            assert_eq!(
+                problems,
+                ["--tls-cert and --tls-key have to be given together"]
+            );
+        }
+    }
 }
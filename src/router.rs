@@ -0,0 +1,182 @@
+//! Matching requests to the handlers registered for their method and path.
+
+use crate::config::Config;
+use crate::handlers::{handle_options, method_not_allowed};
+use crate::http::request::Request;
+use crate::http::response::{error_response, Response};
+use crate::http::StatusCode;
+use std::time::Duration;
+
+type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+type Check = Box<dyn Fn(&Request) -> Option<Response> + Send + Sync>;
+
+struct Route {
+    method: String,
+    pattern: String,
+    handler: Handler,
+    /// Turns a request down before its body is read.
+    check: Option<Check>,
+    timeout: Option<Duration>,
+}
+
+impl Route {
+    /// A pattern ending in `*` matches every path that starts with the rest
+    /// of it, any other pattern only that exact path.
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.pattern,
+        }
+    }
+}
+
+/// Handlers for `(method, path pattern)` pairs. Routes registered later
+/// take precedence, so a default route can be replaced. GET routes answer
+/// HEAD too, and OPTIONS is answered from the routes unless it has one of
+/// its own.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            pattern: pattern.to_string(),
+            handler: Box::new(handler),
+            check: None,
+            timeout: None,
+        });
+        self
+    }
+
+    /// Gives the route added last its own write timeout, for responses that
+    /// take longer to send than `--timeout` allows. A `--route-timeout`
+    /// covering the path still takes precedence.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Lets the route added last turn a request down from its head alone,
+    /// with the response `check` returns. A client waiting for
+    /// `100 Continue` then gets that response instead, without sending the
+    /// body.
+    pub fn check_before_body<F>(&mut self, check: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        if let Some(route) = self.routes.last_mut() {
+            route.check = Some(Box::new(check));
+        }
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// The methods `path` supports, or `None` if nothing is routed there.
+    /// For `*` that is every method with a route anywhere.
+    pub(crate) fn allowed_methods(&self, path: &str) -> Option<Vec<&str>> {
+        let mut methods: Vec<&str> = Vec::new();
+        for route in self
+            .routes
+            .iter()
+            .filter(|route| path == "*" || route.matches(path))
+        {
+            if !methods.contains(&route.method.as_str()) {
+                methods.push(&route.method);
+            }
+            if route.method == "GET" && !methods.contains(&"HEAD") {
+                methods.push("HEAD");
+            }
+        }
+        if methods.is_empty() {
+            return None;
+        }
+        if !methods.contains(&"OPTIONS") {
+            methods.push("OPTIONS");
+        }
+        Some(methods)
+    }
+
+    /// The route for `request`, or `None` if no route matches both its
+    /// method and its path.
+    fn find(&self, request: &Request) -> Option<&Route> {
+        let method = match request.method.as_str() {
+            "HEAD" => "GET",
+            method => method,
+        };
+        self.routes
+            .iter()
+            .rev()
+            .find(|route| route.method == method && route.matches(&request.path))
+    }
+
+    /// The write timeout registered with the route for `request`, if any.
+    pub(crate) fn timeout_for(&self, request: &Request) -> Option<Duration> {
+        self.find(request).and_then(|route| route.timeout)
+    }
+
+    /// The response for a request that is turned down before its body is
+    /// read: because nothing is routed for it, or by its route's check.
+    /// `None` if the body is wanted.
+    pub(crate) fn reject_early(&self, request: &Request, config: &Config) -> Option<Response> {
+        match self.find(request) {
+            Some(route) => route.check.as_ref().and_then(|check| check(request)),
+            None => Some(self.unrouted(request, config)),
+        }
+    }
+
+    /// Answers `request` with the handler routed for it. Anything unrouted
+    /// gets a 404, or a 405 if only the method is wrong.
+    pub(crate) fn dispatch(&self, request: &Request, config: &Config) -> Response {
+        match self.find(request) {
+            Some(route) => (route.handler)(request),
+            None => self.unrouted(request, config),
+        }
+    }
+
+    fn unrouted(&self, request: &Request, config: &Config) -> Response {
+        match self.allowed_methods(&request.path) {
+            Some(methods) if request.method == "OPTIONS" => handle_options(&methods, config),
+            Some(methods) => method_not_allowed(request, &methods),
+            None => error_response(StatusCode::NotFound),
+        }
+    }
+}
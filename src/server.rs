@@ -0,0 +1,244 @@
+//! The accept loop that hands connections to a pool of worker threads.
+
+use crate::config::{Config, LogLevel};
+use crate::connection::{apply_response_headers, handle_client};
+use crate::handlers::register_default_routes;
+use crate::http::response::{error_response, CHUNK_SIZE};
+use crate::http::StatusCode;
+use crate::router::Router;
+use anyhow::{bail, Context, Result};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed through a channel, so a flood of
+/// connections queues up instead of spawning unbounded threads.
+struct ThreadPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    fn new(size: usize) -> ThreadPool {
+        assert!(size > 0, "thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().expect("job queue poisoned").recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // Sending only fails once every worker is gone.
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the workers finish the queued jobs, then joins them.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A server for `config`, answering with the built-in routes plus any
+/// added through [`Server::router`].
+pub struct Server {
+    config: Arc<Config>,
+    router: Router,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let config = Arc::new(config);
+        let mut router = Router::new();
+        register_default_routes(&mut router, &config);
+        Server { config, router }
+    }
+
+    /// The routes requests are matched against. Routes added here take
+    /// precedence over the built-in ones.
+    pub fn router(&mut self) -> &mut Router {
+        &mut self.router
+    }
+
+    /// Binds the configured address and serves until shutdown, or a single
+    /// connection with `one_shot`.
+    pub fn run(self) -> Result<()> {
+        let config = &self.config;
+        let listener = TcpListener::bind((config.address, config.port))
+            .with_context(|| format!("Failed to bind {}:{}", config.address, config.port))?;
+        self.serve(listener)
+    }
+
+    /// Serves on an already bound `listener`. In one-shot mode, answering
+    /// with a 5xx counts as failing.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        if self.config.one_shot {
+            let (stream, _) = listener.accept()?;
+            let errors = self.config.stats.server_errors();
+            handle_client(stream, &self.config, &self.router)?;
+            if self.config.stats.server_errors() > errors {
+                bail!("The request was answered with a server error");
+            }
+            return Ok(());
+        }
+        serve(listener, self.config, Arc::new(self.router))
+    }
+}
+
+/// Accepts connections until shutdown starts, then waits up to the grace
+/// period for the connections already in flight to finish before returning.
+fn serve(listener: TcpListener, config: Arc<Config>, router: Arc<Router>) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let pool = ThreadPool::new(config.threads);
+    let stats = Arc::clone(&config.stats);
+
+    while !config.shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Only this connection is lost, not the server.
+                if let Err(e) = stream.set_nonblocking(false) {
+                    eprintln!("Connection failed: {}", e);
+                    continue;
+                }
+                enable_tcp_keepalive(&stream, &config);
+                let config = Arc::clone(&config);
+                let router = Arc::clone(&router);
+                config.stats.connection_opened();
+                pool.execute(move || {
+                    if let Err(e) = handle_client(stream, &config, &router) {
+                        eprintln!("Error handling client: {:#}", e);
+                    }
+                    config.stats.connection_closed();
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => eprintln!("Connection failed: {}", e),
+        }
+    }
+
+    println!(
+        "Shutting down, waiting up to {}s for {} connection(s)",
+        config.grace_period.as_secs(),
+        stats.active_connections()
+    );
+    // Keep listening while we drain so that new clients are told to go away
+    // rather than left waiting in the backlog.
+    let deadline = Instant::now() + config.grace_period;
+    while stats.active_connections() > 0 && Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, _)) => reject_unavailable(stream, &config),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => eprintln!("Connection failed: {}", e),
+        }
+    }
+    drop(listener);
+
+    let remaining = stats.active_connections();
+    if remaining > 0 {
+        eprintln!("Grace period over, abandoning {} connection(s)", remaining);
+        // Joining would block on the stuck workers, so leave them to be torn
+        // down with the process.
+        std::mem::forget(pool);
+    }
+
+    Ok(())
+}
+
+/// Answers a connection that arrived while shutting down with a 503.
+fn reject_unavailable(mut stream: TcpStream, config: &Config) {
+    // A plaintext response means nothing to a client expecting TLS, and a
+    // handshake isn't worth doing just to turn it away.
+    if config.tls.is_some() {
+        return;
+    }
+    let mut response = error_response(StatusCode::ServiceUnavailable);
+    response
+        .headers
+        .push(("Connection".to_string(), "close".to_string()));
+    apply_response_headers(&mut response, config);
+
+    // Closing with the request still unread would reset the connection and
+    // could lose the response, so read whatever the client sent briefly.
+    let result = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(ACCEPT_POLL_INTERVAL)))
+        .and_then(|_| stream.set_write_timeout(Some(ACCEPT_POLL_INTERVAL)))
+        .and_then(|_| response.write_to(&mut stream, false))
+        .and_then(|_| stream.flush())
+        .and_then(|_| stream.shutdown(Shutdown::Write));
+    match result {
+        Ok(()) => {
+            let _ = io::copy(&mut (&mut stream).take(CHUNK_SIZE as u64), &mut io::sink());
+        }
+        Err(e) => eprintln!("Failed to reject connection: {}", e),
+    }
+}
+
+/// Turns on `--tcp-keepalive` for an accepted connection. Where the OS
+/// doesn't support it, the connection just goes without.
+fn enable_tcp_keepalive(stream: &TcpStream, config: &Config) {
+    let Some(idle) = config.tcp_keepalive else {
+        return;
+    };
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        if config.log_level >= LogLevel::Debug {
+            println!("TCP keepalive unavailable: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn thread_pool_runs_every_job_when_outnumbered() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2);
+        for _ in 0..20 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 20);
+    }
+}
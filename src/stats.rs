@@ -0,0 +1,86 @@
+//! Live counters for `/stats`, shared by every connection.
+
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    /// Requests read, counted before they are handled so that a `/stats`
+    /// response includes itself.
+    requests: AtomicU64,
+    /// Responses sent, by status class: 1xx up to 5xx.
+    responses: [AtomicU64; 5],
+    /// Body bytes sent.
+    bytes_sent: AtomicU64,
+    active_connections: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            responses: Default::default(),
+            bytes_sent: AtomicU64::new(0),
+            active_connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_response(&self, status: u16, sent: u64) {
+        if let Some(count) = self
+            .responses
+            .get(usize::from(status / 100).wrapping_sub(1))
+        {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    /// How many 5xx responses have been sent.
+    pub fn server_errors(&self) -> u64 {
+        self.responses[4].load(Ordering::Relaxed)
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// The counters as the JSON body of `/stats`.
+    pub(crate) fn to_json(&self) -> String {
+        let responses = |class: usize| self.responses[class - 1].load(Ordering::Relaxed);
+        format!(
+            "{{\"requests\":{},\"responses\":{{\"1xx\":{},\"2xx\":{},\"3xx\":{},\"4xx\":{},\"5xx\":{}}},\
+             \"bytes_sent\":{},\"active_connections\":{},\"uptime_seconds\":{}}}",
+            self.requests.load(Ordering::Relaxed),
+            responses(1),
+            responses(2),
+            responses(3),
+            responses(4),
+            responses(5),
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.active_connections(),
+            self.started.elapsed().as_secs()
+        )
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
@@ -0,0 +1,181 @@
+//! Helpers shared by the unit tests.
+
+use crate::config::Config;
+use crate::connection::apply_response_headers;
+use crate::handlers::register_default_routes;
+use crate::http::request::{read_chunked_body, read_request, Request};
+use crate::router::Router;
+use std::{
+    env, fs,
+    io::{self, BufRead, Cursor, Read, Write},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// A directory under the system temp dir, removed again on drop.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "http-server-test-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).expect("create temp dir");
+        TempDir(path)
+    }
+
+    /// The directory as `--directory` takes it.
+    pub(crate) fn directory(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+
+    /// Writes `contents` to `name` inside the directory, creating any
+    /// parent directories on the way.
+    pub(crate) fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        fs::create_dir_all(path.parent().expect("parent")).expect("create parent");
+        fs::write(&path, contents).expect("write file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A connection held in memory: requests are read from `input`, and
+/// whatever the server writes ends up in `output`.
+pub(crate) struct Duplex {
+    pub(crate) input: Cursor<Vec<u8>>,
+    pub(crate) output: Vec<u8>,
+}
+
+impl Duplex {
+    pub(crate) fn new(input: impl Into<Vec<u8>>) -> Self {
+        Duplex {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl BufRead for Duplex {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.input.consume(amount)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses `raw`, a complete request, the way the server would.
+pub(crate) fn request(raw: &str) -> Request {
+    read_request(&mut Duplex::new(raw), &Config::default())
+        .expect("valid request")
+        .expect("a request")
+}
+
+/// A response as it went out on the wire, split into its parts.
+#[derive(Debug)]
+pub(crate) struct Wire {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Wire {
+    /// Splits the bytes of a single response, leaving the body as sent.
+    pub(crate) fn parse(bytes: &[u8]) -> Wire {
+        let end = bytes
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("end of head");
+        let head = String::from_utf8(bytes[..end].to_vec()).expect("ASCII head");
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .expect("status line");
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':').expect("header line");
+                (name.to_string(), value.trim().to_string())
+            })
+            .collect();
+        Wire {
+            status,
+            headers,
+            body: bytes[end + 4..].to_vec(),
+        }
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// How many times the header `name` was sent.
+    pub(crate) fn count(&self, name: &str) -> usize {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .count()
+    }
+
+    /// The body with its chunked framing taken off.
+    pub(crate) fn dechunk(&self) -> Vec<u8> {
+        read_chunked_body(&mut Cursor::new(&self.body), usize::MAX).expect("chunked body")
+    }
+}
+
+/// Answers `raw` with the built-in routes, as the connection would apart
+/// from keep-alive, and returns what went out on the wire.
+pub(crate) fn respond(raw: &str, config: Config) -> Wire {
+    let config = Arc::new(config);
+    let mut router = Router::new();
+    register_default_routes(&mut router, &config);
+
+    let mut conn = Duplex::new(raw);
+    let request = read_request(&mut conn, &config)
+        .expect("valid request")
+        .expect("a request");
+    let mut response = router.dispatch(&request, &config);
+    apply_response_headers(&mut response, &config);
+    let chunked = request.version == "HTTP/1.1";
+    if request.method == "HEAD" {
+        response.strip_body(chunked);
+    }
+    response
+        .write_to(&mut conn.output, chunked)
+        .expect("write to memory");
+    Wire::parse(&conn.output)
+}
@@ -0,0 +1,29 @@
+//! Serving over TLS with `--tls-cert` and `--tls-key`.
+
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+/// Builds the TLS settings from a PEM certificate chain and private key.
+pub fn load_server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert).with_context(|| format!("Failed to open {}", cert.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Failed to read certificates from {}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", cert.display());
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key).with_context(|| format!("Failed to open {}", key.display()))?,
+    ))
+    .with_context(|| format!("Failed to read private key from {}", key.display()))?
+    .with_context(|| format!("No private key in {}", key.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Certificate and key don't match")?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,188 @@
+//! A server on an ephemeral port, and helpers for talking to it over TCP.
+
+// Each test binary uses only some of these.
+#![allow(dead_code)]
+
+use anyhow::Result;
+use http_server_starter_rust::config::{Config, LogLevel};
+use http_server_starter_rust::{Router, Server};
+use std::{
+    env, fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// A quiet configuration that shuts down quickly.
+pub fn config() -> Config {
+    Config {
+        log_level: LogLevel::Error,
+        grace_period: Duration::from_millis(200),
+        ..Config::default()
+    }
+}
+
+/// A server running on its own thread until it is stopped or dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<()>>>,
+}
+
+impl TestServer {
+    pub fn start(config: Config) -> Self {
+        Self::with_routes(config, |_| {})
+    }
+
+    /// Starts a server with routes added by `routes` on top of the built-in
+    /// ones.
+    pub fn with_routes(config: Config, routes: impl FnOnce(&mut Router)) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind ephemeral port");
+        let addr = listener.local_addr().expect("local address");
+        let shutdown = Arc::clone(&config.shutdown);
+        let mut server = Server::new(config);
+        routes(server.router());
+        let thread = thread::spawn(move || server.serve(listener));
+        TestServer {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .expect("set read timeout");
+        stream
+    }
+
+    /// Sends `raw` on a new connection and reads until the server closes it,
+    /// so `raw` should end with a request that closes the connection.
+    pub fn exchange(&self, raw: impl AsRef<[u8]>) -> Vec<u8> {
+        let mut stream = self.connect();
+        stream.write_all(raw.as_ref()).expect("send request");
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).expect("read response");
+        received
+    }
+
+    /// Sends a single request and parses the response to it.
+    pub fn request(&self, raw: impl AsRef<[u8]>) -> Wire {
+        Wire::parse(&self.exchange(raw))
+    }
+
+    /// Starts the shutdown and waits for the server to return.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.thread
+            .take()
+            .expect("server thread")
+            .join()
+            .expect("server panicked")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A response as it went out on the wire, split into its parts.
+#[derive(Debug)]
+pub struct Wire {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Wire {
+    /// Splits the bytes of a single response, leaving the body as sent.
+    pub fn parse(bytes: &[u8]) -> Wire {
+        let end = bytes
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap_or_else(|| panic!("no end of head in {:?}", String::from_utf8_lossy(bytes)));
+        let head = String::from_utf8(bytes[..end].to_vec()).expect("ASCII head");
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .expect("status line");
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':').expect("header line");
+                (name.to_string(), value.trim().to_string())
+            })
+            .collect();
+        Wire {
+            status,
+            headers,
+            body: bytes[end + 4..].to_vec(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A directory under the system temp dir, removed again on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "http-server-it-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).expect("create temp dir");
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The directory as `--directory` takes it.
+    pub fn directory(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+
+    /// Writes `contents` to `name` inside the directory, creating any
+    /// parent directories on the way.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        fs::create_dir_all(path.parent().expect("parent")).expect("create parent");
+        fs::write(&path, contents).expect("write file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
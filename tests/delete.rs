@@ -0,0 +1,37 @@
+mod common;
+
+use common::{config, TempDir, TestServer};
+use http_server_starter_rust::config::Config;
+use pretty_assertions::assert_eq;
+
+fn delete(server: &TestServer, target: &str) -> u16 {
+    server
+        .request(format!(
+            "DELETE {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            target
+        ))
+        .status
+}
+
+#[test]
+fn delete_stays_inside_the_directory() {
+    let outer = TempDir::new();
+    let kept = outer.write("kept.txt", "still here");
+    let doomed = outer.write("served/doomed.txt", "going");
+    outer.write("served/sub/file.txt", "nested");
+    let server = TestServer::start(Config {
+        directory: outer.directory() + "/served",
+        ..config()
+    });
+
+    assert_eq!(delete(&server, "/files/doomed.txt"), 204);
+    assert!(!doomed.exists());
+    assert_eq!(delete(&server, "/files/doomed.txt"), 404);
+
+    assert_eq!(delete(&server, "/files/../kept.txt"), 404);
+    assert_eq!(delete(&server, "/files/%2e%2e/kept.txt"), 404);
+    assert!(kept.exists());
+
+    assert_eq!(delete(&server, "/files/sub"), 409);
+    assert!(outer.path().join("served/sub/file.txt").exists());
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::{config, TempDir, TestServer};
+use http_server_starter_rust::config::Config;
+use pretty_assertions::assert_eq;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+const RATE: u64 = 40_000;
+
+#[test]
+fn parallel_downloads_share_the_client_rate() {
+    let dir = TempDir::new();
+    dir.write("big.bin", vec![b'a'; RATE as usize]);
+    let server = TestServer::start(Config {
+        directory: dir.directory(),
+        download_rate: Some(RATE),
+        ..config()
+    });
+
+    let server = &server;
+    let started = Instant::now();
+    let statuses: Vec<u16> = thread::scope(|scope| {
+        let downloads: Vec<_> = [
+            "GET /files/big.bin HTTP/1.1\r\nConnection: close\r\n\r\n",
+            "GET /files/big.bin HTTP/1.1\r\nRange: bytes=0-19999\r\nConnection: close\r\n\r\n",
+            "GET /files/big.bin HTTP/1.1\r\nRange: bytes=20000-\r\nConnection: close\r\n\r\n",
+        ]
+        .into_iter()
+        .map(|raw| scope.spawn(move || server.request(raw).status))
+        .collect();
+        downloads
+            .into_iter()
+            .map(|download| download.join().unwrap())
+            .collect()
+    });
+
+    assert_eq!(statuses, [200, 206, 206]);
+    // Twice the rate's worth of bytes in all, where each download alone
+    // would be done within a second.
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::{config, TempDir, TestServer, Wire};
+use http_server_starter_rust::auth::Credentials;
+use http_server_starter_rust::config::Config;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+};
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+fn upload_head(path: &str) -> String {
+    format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n\
+         Content-Length: 5\r\n\r\n",
+        path
+    )
+}
+
+/// Reads what the server sends before the client sends anything more,
+/// which ends at the first blank line.
+fn read_head(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    let mut byte = [0];
+    while !received.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).unwrap() == 0 {
+            break;
+        }
+        received.push(byte[0]);
+    }
+    received
+}
+
+#[test]
+fn continue_comes_before_the_body_is_sent() {
+    let dir = TempDir::new();
+    let server = TestServer::start(Config {
+        directory: dir.directory(),
+        ..config()
+    });
+    let mut stream = server.connect();
+    stream
+        .write_all(upload_head("/files/new.txt").as_bytes())
+        .unwrap();
+    assert_eq!(read_head(&mut stream), CONTINUE);
+
+    stream.write_all(b"hello").unwrap();
+    assert_eq!(Wire::parse(&read_head(&mut stream)).status, 201);
+    assert_eq!(std::fs::read(dir.path().join("new.txt")).unwrap(), b"hello");
+}
+
+#[test]
+fn a_refused_upload_never_gets_continue() {
+    let dir = TempDir::new();
+    dir.write("taken.txt", "first");
+    let auth_file = dir.write("users", "");
+    let credentials = Credentials::new(auth_file);
+    credentials.reload().unwrap();
+
+    let open = TestServer::start(Config {
+        directory: dir.directory(),
+        ..config()
+    });
+    let locked = TestServer::start(Config {
+        directory: dir.directory(),
+        credentials: Some(Arc::new(credentials)),
+        ..config()
+    });
+    for (server, path, status) in [
+        (&locked, "/files/new.txt", 401),
+        (&open, "/files/taken.txt", 409),
+        (&open, "/files/", 409),
+        (&open, "/echo/abc", 405),
+        (&open, "/nowhere", 404),
+    ] {
+        let mut stream = server.connect();
+        stream.write_all(upload_head(path).as_bytes()).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+
+        let wire = Wire::parse(&received);
+        assert_eq!(wire.status, status, "POST {}", path);
+        assert_eq!(wire.header("Connection"), Some("close"));
+    }
+    assert_eq!(
+        std::fs::read(dir.path().join("taken.txt")).unwrap(),
+        b"first"
+    );
+    assert!(!dir.path().join("new.txt").exists());
+}
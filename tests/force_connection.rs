@@ -0,0 +1,48 @@
+mod common;
+
+use common::{config, TestServer, Wire};
+use http_server_starter_rust::config::{Config, ForceConnection};
+use pretty_assertions::assert_eq;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+
+/// Sends two requests on one connection, each asking for the opposite of
+/// what is forced, and returns everything that comes back.
+fn two_requests(force: ForceConnection, connection: &str) -> Vec<u8> {
+    let server = TestServer::start(Config {
+        force_connection: Some(force),
+        ..config()
+    });
+    let mut stream = server.connect();
+    for path in ["/echo/one", "/echo/two"] {
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nConnection: {}\r\n\r\n",
+            path, connection
+        )
+        .unwrap();
+    }
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    received
+}
+
+#[test]
+fn forced_close_ends_a_keep_alive_connection() {
+    let received = two_requests(ForceConnection::Close, "keep-alive");
+    let wire = Wire::parse(&received);
+    assert_eq!(wire.header("Connection"), Some("close"));
+    assert_eq!(wire.body, b"one");
+}
+
+#[test]
+fn forced_keep_alive_overrides_the_client() {
+    let received = two_requests(ForceConnection::KeepAlive, "close");
+    let first = Wire::parse(&received);
+    assert_eq!(first.header("Connection"), Some("keep-alive"));
+    assert_eq!(&first.body[..3], b"one");
+    let second = Wire::parse(&first.body[3..]);
+    assert_eq!(second.header("Connection"), Some("keep-alive"));
+    assert_eq!(second.body, b"two");
+}
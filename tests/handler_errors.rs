@@ -0,0 +1,45 @@
+mod common;
+
+use common::{config, TempDir, TestServer};
+use http_server_starter_rust::config::Config;
+use pretty_assertions::assert_eq;
+use std::{fs, os::unix::fs::PermissionsExt};
+
+fn upload(server: &TestServer, name: &str) -> u16 {
+    server
+        .request(format!(
+            "POST /files/{} HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndata",
+            name
+        ))
+        .status
+}
+
+#[test]
+fn a_read_only_directory_refuses_uploads_with_403() {
+    let dir = TempDir::new();
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
+    // Root ignores the mode bits, so check what the server will run into.
+    let denied = fs::write(dir.path().join("probe"), "").is_err();
+    let _ = fs::remove_file(dir.path().join("probe"));
+    let server = TestServer::start(Config {
+        directory: dir.directory(),
+        ..config()
+    });
+
+    let status = upload(&server, "x");
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(status, if denied { 403 } else { 201 });
+}
+
+#[test]
+fn a_failed_write_is_answered_with_500() {
+    let dir = TempDir::new();
+    let server = TestServer::start(Config {
+        directory: dir.directory(),
+        ..config()
+    });
+
+    // Short enough to be a file name, but not once made into the name of
+    // the temporary file it is written to first.
+    assert_eq!(upload(&server, &"a".repeat(250)), 500);
+}
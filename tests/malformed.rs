@@ -0,0 +1,37 @@
+mod common;
+
+use common::{config, TestServer};
+use pretty_assertions::assert_eq;
+use std::io::Read;
+use std::net::Shutdown;
+
+#[test]
+fn a_one_token_request_line_is_answered_with_400() {
+    let server = TestServer::start(config());
+    let wire = server.request("GET\r\n\r\n");
+    assert_eq!(wire.status, 400);
+}
+
+#[test]
+fn an_empty_request_is_left_unanswered() {
+    let server = TestServer::start(config());
+    let mut stream = server.connect();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(received.is_empty());
+}
+
+#[test]
+fn whitespace_before_the_colon_is_answered_with_400() {
+    let server = TestServer::start(config());
+    for head in [
+        "POST /files/x HTTP/1.1\r\nTransfer-Encoding : chunked\r\n",
+        "GET / HTTP/1.1\r\nHost\t: localhost\r\n",
+        "GET / HTTP/1.1\r\n: no name\r\n",
+    ] {
+        let wire = server.request(format!("{}Connection: close\r\n\r\n", head));
+        assert_eq!(wire.status, 400, "{:?}", head);
+    }
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::{config, TestServer};
+use http_server_starter_rust::config::Config;
+use http_server_starter_rust::http::response::Response;
+use http_server_starter_rust::http::StatusCode;
+
+fn one_shot() -> TestServer {
+    TestServer::with_routes(
+        Config {
+            one_shot: true,
+            ..config()
+        },
+        |router| {
+            router.get("/broken", |_| {
+                Response::new(StatusCode::InternalServerError)
+            });
+        },
+    )
+}
+
+#[test]
+fn one_shot_succeeds_after_a_good_response() {
+    let server = one_shot();
+    let wire = server.request("GET /echo/hi HTTP/1.1\r\n\r\n");
+    assert_eq!(wire.status, 200);
+    assert!(server.stop().is_ok());
+}
+
+#[test]
+fn one_shot_fails_after_a_server_error() {
+    let server = one_shot();
+    let wire = server.request("GET /broken HTTP/1.1\r\n\r\n");
+    assert_eq!(wire.status, 500);
+    assert!(server.stop().is_err());
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::{config, TestServer, Wire};
+use http_server_starter_rust::config::{BodyPolicy, Config};
+use pretty_assertions::assert_eq;
+use std::{
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+const GET_WITH_A_BODY_THEN_ANOTHER: &str =
+    "GET /echo/first HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                                            GET /echo/second HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn an_ignored_get_body_is_drained_before_the_next_request() {
+    let server = TestServer::start(config());
+    let received = server.exchange(GET_WITH_A_BODY_THEN_ANOTHER);
+
+    let first = Wire::parse(&received);
+    assert_eq!(first.status, 200);
+    assert_eq!(&first.body[..5], b"first");
+    let second = Wire::parse(&first.body[5..]);
+    assert_eq!(second.status, 200);
+    assert_eq!(second.body, b"second");
+}
+
+#[test]
+fn a_rejected_get_body_ends_the_connection() {
+    let server = TestServer::start(Config {
+        body_policy: BodyPolicy::Reject,
+        ..config()
+    });
+    let received = server.exchange(GET_WITH_A_BODY_THEN_ANOTHER);
+
+    let wire = Wire::parse(&received);
+    assert_eq!(wire.status, 400);
+    let body = String::from_utf8_lossy(&wire.body);
+    assert!(!body.contains("HTTP/1.1"), "{}", body);
+}
+
+#[test]
+fn an_oversized_body_is_refused_before_it_is_sent() {
+    let server = TestServer::start(Config {
+        max_body: 1024,
+        ..config()
+    });
+    let mut stream = server.connect();
+    let started = Instant::now();
+    stream
+        .write_all(b"POST /files/big HTTP/1.1\r\nContent-Length: 1000000000000\r\n\r\n")
+        .unwrap();
+
+    // Nothing of the body is sent, so an answer can only come from the
+    // head alone.
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(Wire::parse(&received).status, 413);
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::{config, TestServer};
+use http_server_starter_rust::config::Config;
+use http_server_starter_rust::http::request::Request;
+use http_server_starter_rust::http::response::{Body, Response};
+use http_server_starter_rust::Router;
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+
+/// Larger than the socket buffers can hold, so sending it blocks for as long
+/// as the client isn't reading.
+const BODY_LEN: u64 = 64 * 1024 * 1024;
+
+fn big_download(_: &Request) -> Response {
+    let mut response = Response::ok();
+    response.body = Body::Reader(Box::new(io::repeat(b'a').take(BODY_LEN)), Some(BODY_LEN));
+    response
+}
+
+fn routes(router: &mut Router) {
+    router.get("/quick", big_download);
+    router
+        .get("/slow", big_download)
+        .timeout(Duration::from_secs(30));
+}
+
+/// How many bytes a client that only starts reading after a pause gets. The
+/// pause is well past the 1s write timeout, so a worker that is slow to get
+/// going on a busy machine still times out before the client reads.
+fn received_after_pause(server: &TestServer, path: &str) -> usize {
+    let mut stream = server.connect();
+    write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).unwrap();
+    thread::sleep(Duration::from_secs(4));
+    let mut received = 0;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return received,
+            Ok(n) => received += n,
+        }
+    }
+}
+
+#[test]
+fn a_slow_route_gets_its_own_write_timeout() {
+    let server = TestServer::with_routes(
+        Config {
+            timeout: Duration::from_secs(1),
+            ..config()
+        },
+        routes,
+    );
+    let (quick, slow) = thread::scope(|scope| {
+        let quick = scope.spawn(|| received_after_pause(&server, "/quick"));
+        let slow = scope.spawn(|| received_after_pause(&server, "/slow"));
+        (quick.join().unwrap(), slow.join().unwrap())
+    });
+
+    // The head is tiny next to the body, so a complete response is only a
+    // little over BODY_LEN.
+    assert!(slow as u64 > BODY_LEN, "slow route got {} bytes", slow);
+    assert!((quick as u64) < BODY_LEN, "quick route got {} bytes", quick);
+}
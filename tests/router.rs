@@ -0,0 +1,32 @@
+mod common;
+
+use common::{config, TestServer};
+use http_server_starter_rust::http::response::Response;
+
+#[test]
+fn a_custom_route_answers_over_tcp() {
+    let server = TestServer::with_routes(config(), |router| {
+        router.get("/ping", |_| {
+            Response::ok().content_type("text/plain").body("pong")
+        });
+        // Registered later, so it replaces the built-in echo.
+        router.get("/echo/*", |request| {
+            Response::ok()
+                .content_type("text/plain")
+                .body(request.path.to_uppercase())
+        });
+    });
+
+    let ping = server.request("GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(ping.status, 200);
+    assert_eq!(ping.header("Content-Length"), Some("4"));
+    assert_eq!(ping.body, b"pong");
+
+    let echo = server.request("GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(echo.body, b"/ECHO/ABC");
+
+    // The built-in routes are still there.
+    let agent =
+        server.request("GET /user-agent HTTP/1.1\r\nUser-Agent: test\r\nConnection: close\r\n\r\n");
+    assert_eq!(agent.body, b"test");
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use common::{config, TestServer};
+use pretty_assertions::assert_eq;
+use std::{
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn shutdown_returns_cleanly_and_stops_accepting() {
+    let server = TestServer::start(config());
+    let addr = server.addr();
+    let wire = server.request("GET /echo/up HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(wire.body, b"up");
+
+    let started = Instant::now();
+    server.stop().expect("clean shutdown");
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(TcpStream::connect(addr).is_err());
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::{config, TestServer};
+use pretty_assertions::assert_eq;
+use std::thread;
+
+const PARALLEL: usize = 16;
+
+#[test]
+fn parallel_requests_are_all_counted() {
+    let server = TestServer::start(config());
+    thread::scope(|scope| {
+        for _ in 0..PARALLEL {
+            scope.spawn(|| {
+                let wire = server.request("GET /echo/count HTTP/1.1\r\nConnection: close\r\n\r\n");
+                assert_eq!(wire.status, 200);
+            });
+        }
+    });
+
+    let wire = server.request("GET /stats HTTP/1.1\r\nConnection: close\r\n\r\n");
+    let stats = String::from_utf8(wire.body).unwrap();
+    // The stats request counts itself.
+    let expected = format!("{{\"requests\":{},", PARALLEL + 1);
+    assert!(stats.starts_with(&expected), "{}", stats);
+    let expected = format!("\"2xx\":{},", PARALLEL);
+    assert!(stats.contains(&expected), "{}", stats);
+}
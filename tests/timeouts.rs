@@ -0,0 +1,74 @@
+mod common;
+
+use common::{config, TestServer, Wire};
+use http_server_starter_rust::config::Config;
+use std::{
+    io::{Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn half_a_request_line_times_out_with_408() {
+    let server = TestServer::start(Config {
+        timeout: Duration::from_secs(1),
+        ..config()
+    });
+    let mut stream = server.connect();
+    let started = Instant::now();
+    stream.write_all(b"GET /ech").unwrap();
+
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(Wire::parse(&received).status, 408);
+}
+
+#[test]
+fn a_trickled_request_times_out_with_408() {
+    let server = TestServer::start(Config {
+        timeout: Duration::from_secs(1),
+        threads: 1,
+        ..config()
+    });
+    let mut stream = server.connect();
+    let started = Instant::now();
+    // Each byte arrives well within the read timeout, but all of them
+    // together take far longer. Stop once the server has answered, as
+    // writing to a closed connection would reset it.
+    for byte in b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n" {
+        stream.write_all(&[*byte]).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        stream.set_nonblocking(true).unwrap();
+        let answered = stream.peek(&mut [0]).is_ok();
+        stream.set_nonblocking(false).unwrap();
+        if answered {
+            break;
+        }
+    }
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(4));
+    assert_eq!(Wire::parse(&received).status, 408);
+}
+
+#[test]
+fn a_silent_connection_frees_its_worker() {
+    let server = TestServer::start(Config {
+        timeout: Duration::from_secs(1),
+        threads: 1,
+        ..config()
+    });
+    let mut silent = server.connect();
+    let started = Instant::now();
+
+    // Nothing was asked, so nothing is answered.
+    let mut received = Vec::new();
+    silent.read_to_end(&mut received).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert!(received.is_empty());
+
+    // With only one worker, this is answered only if it has moved on.
+    let wire = server.request("GET /echo/back HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(wire.body, b"back");
+}
@@ -0,0 +1,111 @@
+//! Requests over TLS, against the self-signed `localhost` certificate in
+//! `tests/fixtures`, made with:
+//!
+//! ```text
+//! openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+//!     -keyout localhost.key -out localhost.pem -days 36500 -subj /CN=localhost \
+//!     -addext subjectAltName=DNS:localhost -addext basicConstraints=critical,CA:FALSE
+//! ```
+
+mod common;
+
+use common::{config, TestServer, Wire};
+use http_server_starter_rust::config::Config;
+use http_server_starter_rust::tls::load_server_config;
+use pretty_assertions::assert_eq;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::{
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn tls_config() -> Config {
+    let fixtures = Path::new(FIXTURES);
+    Config {
+        tls: Some(
+            load_server_config(
+                &fixtures.join("localhost.pem"),
+                &fixtures.join("localhost.key"),
+            )
+            .expect("fixture certificate"),
+        ),
+        ..config()
+    }
+}
+
+/// A client that trusts only the fixture certificate.
+fn client() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let pem = File::open(Path::new(FIXTURES).join("localhost.pem")).expect("open fixture");
+    for cert in rustls_pemfile::certs(&mut BufReader::new(pem)) {
+        let cert: CertificateDer = cert.expect("fixture certificate");
+        roots.add(cert).expect("trust fixture certificate");
+    }
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Sends `raw` over a TLS connection set up for `sni` and reads until the
+/// server closes it.
+fn tls_request(server: &TestServer, sni: &str, raw: &str) -> Wire {
+    let name = ServerName::try_from(sni.to_string()).expect("server name");
+    let conn = ClientConnection::new(client(), name).expect("client connection");
+    let mut stream = StreamOwned::new(conn, server.connect());
+    stream.write_all(raw.as_bytes()).expect("send request");
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).expect("read response");
+    Wire::parse(&received)
+}
+
+#[test]
+fn a_host_other_than_the_server_name_is_misdirected() {
+    let server = TestServer::start(tls_config());
+
+    let wire = tls_request(
+        &server,
+        "localhost",
+        "GET / HTTP/1.1\r\nHost: other.example\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(wire.status, 421);
+
+    let wire = tls_request(
+        &server,
+        "localhost",
+        "GET / HTTP/1.1\r\nHost: LOCALHOST:4221\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(wire.status, 200);
+}
+
+#[test]
+fn echo_is_served_over_tls() {
+    let server = TestServer::start(tls_config());
+    let wire = tls_request(
+        &server,
+        "localhost",
+        "GET /echo/hi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(wire.status, 200);
+    assert_eq!(wire.body, b"hi");
+}
+
+#[test]
+fn a_failed_handshake_leaves_the_server_running() {
+    let server = TestServer::start(tls_config());
+    let received = server.exchange("GET /echo/hi HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(!received.starts_with(b"HTTP/"));
+
+    let wire = tls_request(
+        &server,
+        "localhost",
+        "GET /echo/still HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(wire.body, b"still");
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{config, TestServer};
+use http_server_starter_rust::config::Config;
+use http_server_starter_rust::http::response::{Body, Response};
+use pretty_assertions::assert_eq;
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Larger than the socket buffers can hold, so sending it blocks for as long
+/// as the client isn't reading.
+const BODY_LEN: u64 = 64 * 1024 * 1024;
+
+#[test]
+fn a_client_that_stops_reading_is_disconnected() {
+    let server = TestServer::with_routes(
+        Config {
+            write_timeout: Some(Duration::from_millis(500)),
+            threads: 1,
+            ..config()
+        },
+        |router| {
+            router.get("/download", |_| {
+                let mut response = Response::ok();
+                response.body =
+                    Body::Reader(Box::new(io::repeat(b'a').take(BODY_LEN)), Some(BODY_LEN));
+                response
+            });
+        },
+    );
+    let mut stalled = server.connect();
+    write!(
+        stalled,
+        "GET /download HTTP/1.1\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(1500));
+
+    // The only worker has given up on the stalled download, so it is free
+    // to answer someone else.
+    let started = Instant::now();
+    let wire = server.request("GET /echo/next HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(wire.body, b"next");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let mut received = 0;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match stalled.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n,
+        }
+    }
+    assert!((received as u64) < BODY_LEN, "got {} bytes", received);
+}